@@ -0,0 +1,816 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::{bail, ensure, eyre, Report, Result};
+use hyper::{
+    body::{to_bytes, Bytes},
+    client::connect::{Connected, Connection, HttpConnector},
+    header::{CONTENT_TYPE, LOCATION},
+    http::uri::Scheme,
+    service::Service,
+    Body, Client, HeaderMap, Method, Request, StatusCode, Uri,
+};
+use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    rsa::Rsa,
+    sha::sha256,
+    ssl::{SslConnector, SslMethod},
+    stack::Stack,
+    x509::{
+        extension::SubjectAlternativeName, X509Extension, X509Name, X509NameRef, X509Req, X509,
+    },
+};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    sync::mpsc::Sender,
+    time::sleep,
+};
+use tokio_openssl::SslStream;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    config::{AcmeChallengeType, AcmeConfig, Domain},
+    global_state::{uncovered_hosts, GlobalState, Notify},
+};
+
+/// Link in the directory of a domain to the version directory holding its current certificate
+const CURRENT_LINK: &str = "current";
+/// How often the certificates are checked for renewal
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// First delay before a failed renewal is retried, doubled on every further failure
+const RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Time between polls of a pending authorization or order
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+/// ALPN protocol name of TLS-ALPN-01 challenges (RFC 8737)
+pub const ACME_TLS_ALPN_PROTOCOL: &[u8] = b"acme-tls/1";
+/// Path prefix of HTTP-01 challenges
+pub const ACME_HTTP_CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+/// Challenge responses that are currently being validated, shared with the listeners
+#[derive(Default)]
+pub struct AcmeChallenges {
+    /// Key authorizations of HTTP-01 challenges by token
+    http: Mutex<HashMap<String, String>>,
+    /// Certificates answering TLS-ALPN-01 challenges by host
+    tls_alpn: Mutex<HashMap<String, (X509, PKey<Private>)>>,
+}
+
+impl AcmeChallenges {
+    pub fn http_key_authorization(&self, token: &str) -> Option<String> {
+        self.http.lock().unwrap().get(token).cloned()
+    }
+
+    pub fn tls_alpn_certificate(&self, host: &str) -> Option<(X509, PKey<Private>)> {
+        self.tls_alpn.lock().unwrap().get(host).cloned()
+    }
+}
+
+/// Where the certificate (chain) and key of an ACME managed domain are stored: in the version
+/// directory the `current` link points to, so that both can be replaced together
+pub fn cert_paths(acme: &AcmeConfig, domain: &Domain) -> (PathBuf, PathBuf) {
+    let dir = cert_dir(acme, domain).join(CURRENT_LINK);
+    (dir.join("chain.pem"), dir.join("key.pem"))
+}
+
+fn cert_dir(acme: &AcmeConfig, domain: &Domain) -> PathBuf {
+    Path::new(&acme.storage_path).join(&domain.email_domain)
+}
+
+/// Creates a self-signed certificate for an ACME managed domain that has none yet, so that the
+/// state can be loaded before the first certificate has been issued
+pub async fn ensure_placeholder(acme: &AcmeConfig, domain: &Domain) -> Result<()> {
+    let (chain_path, key_path) = cert_paths(acme, domain);
+    if chain_path.exists() && key_path.exists() {
+        return Ok(());
+    }
+    info!(
+        "No certificate for ACME managed domain {} yet, creating a self-signed placeholder",
+        domain.email_domain
+    );
+    let key = new_certificate_key()?;
+    let cert = self_signed(&domain.exact_hosts(), &key, None)?;
+    store_certificate(
+        &cert_dir(acme, domain),
+        &cert.to_pem()?,
+        &key.private_key_to_pem_pkcs8()?,
+    )
+    .await
+}
+
+/// Starts the task that requests and renews the certificates of all ACME managed domains and
+/// reloads the state after a certificate has changed. Failed requests are retried with a backoff
+/// from [`RETRY_INTERVAL`] up to [`CHECK_INTERVAL`].
+pub fn spawn(global_state: Arc<GlobalState>, reload: Sender<Notify>) {
+    tokio::spawn(async move {
+        let mut retry_interval = RETRY_INTERVAL;
+        loop {
+            let renewals = renew_certificates(&global_state)
+                .await
+                .unwrap_or_else(|err| {
+                    error!("ACME certificate renewal failed: {:#}", err);
+                    Renewals {
+                        changed: false,
+                        failed: true,
+                    }
+                });
+            if renewals.changed {
+                if let Err(err) = reload.send(Notify::Reload).await {
+                    error!(
+                        "Could not request reload after certificate renewal: {:#}",
+                        err
+                    );
+                }
+            }
+            if renewals.failed {
+                info!(
+                    "Retrying the ACME certificate renewal in {:?}",
+                    retry_interval
+                );
+                sleep(retry_interval).await;
+                retry_interval = (retry_interval * 2).min(CHECK_INTERVAL);
+            } else {
+                retry_interval = RETRY_INTERVAL;
+                sleep(CHECK_INTERVAL).await;
+            }
+        }
+    });
+}
+
+/// Outcome of a renewal run
+#[derive(Default)]
+struct Renewals {
+    /// A certificate has been stored
+    changed: bool,
+    /// A certificate could not be requested
+    failed: bool,
+}
+
+/// Renews all certificates that are due
+async fn renew_certificates(global_state: &GlobalState) -> Result<Renewals> {
+    // Not a guard, as this is held across the requests to the CA
+    let state = global_state.load_full();
+    let acme = match &state.config.acme {
+        Some(acme) => acme,
+        None => return Ok(Renewals::default()),
+    };
+    let mut client = None;
+    let mut renewals = Renewals::default();
+    for domain in state.config.domains.iter().filter(|d| d.acme_managed()) {
+        let (chain_path, _) = cert_paths(acme, domain);
+        if !needs_renewal(&chain_path, domain, acme.renew_before_days).await? {
+            continue;
+        }
+        info!("Requesting certificate for {}", domain.email_domain);
+        if client.is_none() {
+            client = Some(AcmeClient::new(acme).await?);
+        }
+        let client = client.as_mut().unwrap();
+        match client
+            .issue(&domain.exact_hosts(), acme.challenge, global_state)
+            .await
+        {
+            Ok((chain, key)) => {
+                store_certificate(
+                    &cert_dir(acme, domain),
+                    chain.as_bytes(),
+                    &key.private_key_to_pem_pkcs8()?,
+                )
+                .await?;
+                info!("Stored new certificate for {}", domain.email_domain);
+                renewals.changed = true;
+            }
+            Err(err) => {
+                error!(
+                    "Could not get a certificate for {}: {:#}",
+                    domain.email_domain, err
+                );
+                renewals.failed = true;
+            }
+        }
+    }
+    Ok(renewals)
+}
+
+/// Whether the stored certificate is a placeholder, expires soon or misses one of the hosts
+async fn needs_renewal(chain_path: &Path, domain: &Domain, renew_before_days: u32) -> Result<bool> {
+    let pem = match tokio::fs::read(chain_path).await {
+        Ok(pem) => pem,
+        Err(_) => return Ok(true),
+    };
+    let cert = match X509::from_pem(&pem) {
+        Ok(cert) => cert,
+        Err(err) => {
+            warn!(
+                "Could not read the certificate {:?}, replacing it: {:#}",
+                chain_path, err
+            );
+            return Ok(true);
+        }
+    };
+    if names_equal(cert.issuer_name(), cert.subject_name())? {
+        return Ok(true);
+    }
+    if cert.not_after() < Asn1Time::days_from_now(renew_before_days)? {
+        return Ok(true);
+    }
+    Ok(!uncovered_hosts(&cert, &domain.exact_hosts()).is_empty())
+}
+
+fn names_equal(a: &X509NameRef, b: &X509NameRef) -> Result<bool> {
+    Ok(a.to_der()? == b.to_der()?)
+}
+
+/// Stores a certificate chain and its key in a new version directory in `dir` and switches the
+/// `current` link over to it with a single rename. The previous version is kept until the next
+/// one is stored, so that a state load reading it at the same time still finds both files.
+async fn store_certificate(dir: &Path, chain: &[u8], key: &[u8]) -> Result<()> {
+    let version = SystemTime::now()
+        .duration_since(UNIX_EPOCH)?
+        .as_millis()
+        .to_string();
+    let version_dir = dir.join(&version);
+    tokio::fs::create_dir_all(&version_dir).await?;
+    tokio::fs::write(version_dir.join("chain.pem"), chain).await?;
+    tokio::fs::write(version_dir.join("key.pem"), key).await?;
+
+    let link = dir.join(CURRENT_LINK);
+    let tmp_link = link.with_extension("tmp");
+    let _ = tokio::fs::remove_file(&tmp_link).await;
+    tokio::fs::symlink(&version, &tmp_link).await?;
+    let previous = tokio::fs::read_link(&link).await.ok();
+    tokio::fs::rename(&tmp_link, &link).await?;
+
+    // Version directories are named by their time, so anything else in `dir` is left alone
+    let mut entries = tokio::fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let is_version = name
+            .to_str()
+            .is_some_and(|name| !name.is_empty() && name.bytes().all(|byte| byte.is_ascii_digit()));
+        let keep =
+            [Some(Path::new(&version)), previous.as_deref()].contains(&Some(Path::new(&name)));
+        if is_version && !keep && entry.file_type().await?.is_dir() {
+            if let Err(err) = tokio::fs::remove_dir_all(entry.path()).await {
+                warn!(
+                    "Could not remove the old certificate {:?}: {:#}",
+                    entry.path(),
+                    err
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    tokio::fs::write(&tmp_path, contents).await?;
+    tokio::fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+fn new_certificate_key() -> Result<PKey<Private>> {
+    Ok(PKey::from_rsa(Rsa::generate(2048)?)?)
+}
+
+/// A short lived self-signed certificate for `hosts`, with the given extra extension
+fn self_signed(
+    hosts: &[String],
+    key: &PKey<Private>,
+    extension: Option<X509Extension>,
+) -> Result<X509> {
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(
+        Nid::COMMONNAME,
+        hosts.first().map(String::as_str).unwrap_or("localhost"),
+    )?;
+    let name = name.build();
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let mut serial = BigNum::new()?;
+    serial.rand(128, openssl::bn::MsbOption::MAYBE_ZERO, false)?;
+    builder.set_serial_number(serial.to_asn1_integer()?.as_ref())?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    builder.set_not_before(Asn1Time::days_from_now(0)?.as_ref())?;
+    builder.set_not_after(Asn1Time::days_from_now(7)?.as_ref())?;
+    let mut san = SubjectAlternativeName::new();
+    for host in hosts {
+        san.dns(host);
+    }
+    let san = san.build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+    if let Some(extension) = extension {
+        builder.append_extension(extension)?;
+    }
+    builder.sign(key, MessageDigest::sha256())?;
+    Ok(builder.build())
+}
+
+/// The certificate answering a TLS-ALPN-01 challenge for `host` (RFC 8737)
+fn tls_alpn_certificate(host: &str, key_authorization: &str) -> Result<(X509, PKey<Private>)> {
+    // id-pe-acmeIdentifier, containing the DER of an OCTET STRING with the key authorization hash
+    let oid = Asn1Object::from_str("1.3.6.1.5.5.7.1.31")?;
+    let mut der = vec![0x04, 0x20];
+    der.extend_from_slice(&sha256(key_authorization.as_bytes()));
+    let value = Asn1OctetString::new_from_bytes(&der)?;
+    let extension = X509Extension::new_from_der(&oid, true, &value)?;
+    let key = new_certificate_key()?;
+    let cert = self_signed(&[host.to_owned()], &key, Some(extension))?;
+    Ok((cert, key))
+}
+
+fn base64url(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .replace('+', "-")
+        .replace('/', "_")
+        .trim_end_matches('=')
+        .to_owned()
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    #[serde(default)]
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Challenge {
+    #[serde(rename = "type")]
+    ctype: String,
+    url: String,
+    token: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct Problem {
+    #[serde(rename = "type")]
+    ptype: String,
+    #[serde(default)]
+    detail: String,
+}
+
+/// A minimal ACME (RFC 8555) client
+struct AcmeClient {
+    http: Client<HttpsConnector>,
+    directory: Directory,
+    account_key: EcKey<Private>,
+    /// The account URL, used as key id once the account exists
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl AcmeClient {
+    async fn new(acme: &AcmeConfig) -> Result<Self> {
+        let mut tls = SslConnector::builder(SslMethod::tls_client())?;
+        if let Some(ca_path) = &acme.directory_ca {
+            let ca = tokio::fs::read(ca_path).await?;
+            tls.cert_store_mut().add_cert(X509::from_pem(&ca)?)?;
+        }
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        let http = Client::builder().build(HttpsConnector {
+            http,
+            tls: tls.build(),
+        });
+
+        let response = http.get(acme.directory_url.parse()?).await?;
+        ensure!(
+            response.status().is_success(),
+            "Could not fetch ACME directory: {}",
+            response.status()
+        );
+        let directory = serde_json::from_slice(&to_bytes(response.into_body()).await?)?;
+
+        let account_key_path = Path::new(&acme.storage_path).join("account.pem");
+        let account_key = match tokio::fs::read(&account_key_path).await {
+            Ok(pem) => EcKey::private_key_from_pem(&pem)?,
+            Err(_) => {
+                let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+                let key = EcKey::generate(&group)?;
+                write_atomically(&account_key_path, &key.private_key_to_pem()?).await?;
+                key
+            }
+        };
+
+        let mut this = Self {
+            http,
+            directory,
+            account_key,
+            kid: None,
+            nonce: None,
+        };
+        let contact: Vec<String> = acme
+            .contact
+            .iter()
+            .map(|c| format!("mailto:{}", c))
+            .collect();
+        let new_account = this.directory.new_account.clone();
+        let (_, headers, _) = this
+            .post(
+                &new_account,
+                Some(json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        let kid = headers
+            .get(LOCATION)
+            .ok_or_else(|| eyre!("ACME account response without location"))?
+            .to_str()?
+            .to_owned();
+        debug!("Using ACME account {}", kid);
+        this.kid = Some(kid);
+        Ok(this)
+    }
+
+    /// Orders a certificate for `hosts`, answering the challenges through the listeners of this
+    /// server. Returns the PEM certificate chain and the new private key.
+    async fn issue(
+        &mut self,
+        hosts: &[String],
+        challenge_type: AcmeChallengeType,
+        global_state: &GlobalState,
+    ) -> Result<(String, PKey<Private>)> {
+        let identifiers: Vec<Value> = hosts
+            .iter()
+            .map(|host| json!({ "type": "dns", "value": host }))
+            .collect();
+        let new_order = self.directory.new_order.clone();
+        let (_, headers, body) = self
+            .post(&new_order, Some(json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = headers
+            .get(LOCATION)
+            .ok_or_else(|| eyre!("ACME order response without location"))?
+            .to_str()?
+            .to_owned();
+        let order: Order = serde_json::from_slice(&body)?;
+
+        for authorization_url in &order.authorizations {
+            self.authorize(authorization_url, challenge_type, global_state)
+                .await?;
+        }
+
+        let key = new_certificate_key()?;
+        let mut csr = X509Req::builder()?;
+        let mut name = X509Name::builder()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, &hosts[0])?;
+        csr.set_subject_name(&name.build())?;
+        let mut san = SubjectAlternativeName::new();
+        for host in hosts {
+            san.dns(host);
+        }
+        let mut extensions = Stack::new()?;
+        extensions.push(san.build(&csr.x509v3_context(None))?)?;
+        csr.add_extensions(&extensions)?;
+        csr.set_pubkey(&key)?;
+        csr.sign(&key, MessageDigest::sha256())?;
+        let csr = base64url(&csr.build().to_der()?);
+        self.post(&order.finalize, Some(json!({ "csr": csr })))
+            .await?;
+
+        let mut order: Order;
+        let mut attempts = 0;
+        loop {
+            let (_, _, body) = self.post(&order_url, None).await?;
+            order = serde_json::from_slice(&body)?;
+            match order.status.as_str() {
+                "valid" => break,
+                "invalid" => bail!("ACME order became invalid"),
+                _ => {}
+            }
+            attempts += 1;
+            ensure!(
+                attempts < POLL_ATTEMPTS,
+                "ACME order was not finalized in time"
+            );
+            sleep(POLL_INTERVAL).await;
+        }
+        let certificate_url = order
+            .certificate
+            .ok_or_else(|| eyre!("valid ACME order without certificate"))?;
+        let (_, _, chain) = self.post(&certificate_url, None).await?;
+        Ok((String::from_utf8(chain.to_vec())?, key))
+    }
+
+    async fn authorize(
+        &mut self,
+        authorization_url: &str,
+        challenge_type: AcmeChallengeType,
+        global_state: &GlobalState,
+    ) -> Result<()> {
+        let (_, _, body) = self.post(authorization_url, None).await?;
+        let authorization: Authorization = serde_json::from_slice(&body)?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let host = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|c| c.ctype == challenge_type.name())
+            .ok_or_else(|| {
+                eyre!(
+                    "No {} challenge offered for {}",
+                    challenge_type.name(),
+                    host
+                )
+            })?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint()?);
+
+        let challenges = &global_state.acme_challenges;
+        match challenge_type {
+            AcmeChallengeType::Http01 => {
+                challenges
+                    .http
+                    .lock()
+                    .unwrap()
+                    .insert(challenge.token.clone(), key_authorization);
+            }
+            AcmeChallengeType::TlsAlpn01 => {
+                let certificate = tls_alpn_certificate(&host, &key_authorization)?;
+                challenges
+                    .tls_alpn
+                    .lock()
+                    .unwrap()
+                    .insert(host.to_lowercase(), certificate);
+            }
+        }
+        let result = self
+            .validate(authorization_url, &challenge.url, &host)
+            .await;
+        challenges.http.lock().unwrap().remove(&challenge.token);
+        challenges
+            .tls_alpn
+            .lock()
+            .unwrap()
+            .remove(&host.to_lowercase());
+        result
+    }
+
+    /// Tells the server that the challenge is ready and waits for the authorization result
+    async fn validate(
+        &mut self,
+        authorization_url: &str,
+        challenge_url: &str,
+        host: &str,
+    ) -> Result<()> {
+        self.post(challenge_url, Some(json!({}))).await?;
+        for _ in 0..POLL_ATTEMPTS {
+            sleep(POLL_INTERVAL).await;
+            let (_, _, body) = self.post(authorization_url, None).await?;
+            let authorization: Authorization = serde_json::from_slice(&body)?;
+            match authorization.status.as_str() {
+                "valid" => {
+                    info!("ACME authorization for {} is valid", host);
+                    return Ok(());
+                }
+                "pending" => {}
+                status => bail!("ACME authorization for {} is {}", host, status),
+            }
+        }
+        bail!("ACME authorization for {} was not validated in time", host)
+    }
+
+    fn jwk(&self) -> Result<Value> {
+        let mut x = BigNum::new()?;
+        let mut y = BigNum::new()?;
+        let mut ctx = BigNumContext::new()?;
+        self.account_key.public_key().affine_coordinates(
+            self.account_key.group(),
+            &mut x,
+            &mut y,
+            &mut ctx,
+        )?;
+        Ok(json!({
+            "crv": "P-256",
+            "kty": "EC",
+            "x": base64url(&x.to_vec_padded(32)?),
+            "y": base64url(&y.to_vec_padded(32)?),
+        }))
+    }
+
+    /// JWK thumbprint (RFC 7638) of the account key
+    fn thumbprint(&self) -> Result<String> {
+        // serde_json sorts the keys, which is the order the thumbprint requires
+        let jwk = serde_json::to_string(&self.jwk()?)?;
+        Ok(base64url(&sha256(jwk.as_bytes())))
+    }
+
+    async fn nonce(&mut self) -> Result<String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let request = Request::builder()
+            .method(Method::HEAD)
+            .uri(&self.directory.new_nonce)
+            .body(Body::empty())?;
+        let response = self.http.request(request).await?;
+        Ok(response
+            .headers()
+            .get("Replay-Nonce")
+            .ok_or_else(|| eyre!("ACME server sent no nonce"))?
+            .to_str()?
+            .to_owned())
+    }
+
+    /// Sends a JWS signed POST request, a POST-as-GET if there is no payload
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<Value>,
+    ) -> Result<(StatusCode, HeaderMap, Bytes)> {
+        let mut retried = false;
+        loop {
+            let mut protected = json!({
+                "alg": "ES256",
+                "nonce": self.nonce().await?,
+                "url": url,
+            });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk()?,
+            }
+            let protected = base64url(serde_json::to_string(&protected)?.as_bytes());
+            let payload = match &payload {
+                Some(payload) => base64url(serde_json::to_string(payload)?.as_bytes()),
+                None => String::new(),
+            };
+            let signature = EcdsaSig::sign(
+                &sha256(format!("{}.{}", protected, payload).as_bytes()),
+                &self.account_key,
+            )?;
+            let mut raw_signature = signature.r().to_vec_padded(32)?;
+            raw_signature.extend(signature.s().to_vec_padded(32)?);
+            let body = json!({
+                "protected": protected,
+                "payload": payload,
+                "signature": base64url(&raw_signature),
+            });
+
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(serde_json::to_vec(&body)?))?;
+            let response = self.http.request(request).await?;
+            let status = response.status();
+            let headers = response.headers().clone();
+            if let Some(nonce) = headers.get("Replay-Nonce") {
+                self.nonce = Some(nonce.to_str()?.to_owned());
+            }
+            let body = to_bytes(response.into_body()).await?;
+            if status.is_success() {
+                return Ok((status, headers, body));
+            }
+            let problem: Problem = serde_json::from_slice(&body).unwrap_or(Problem {
+                ptype: String::new(),
+                detail: String::from_utf8_lossy(&body).into_owned(),
+            });
+            if problem.ptype == "urn:ietf:params:acme:error:badNonce" && !retried {
+                warn!("ACME server rejected nonce, retrying");
+                retried = true;
+                continue;
+            }
+            bail!(
+                "ACME request to {} failed with {}: {} {}",
+                url,
+                status,
+                problem.ptype,
+                problem.detail
+            );
+        }
+    }
+}
+
+/// Connects the ACME client to `http` and `https` URLs, with OpenSSL for TLS
+#[derive(Clone)]
+struct HttpsConnector {
+    http: HttpConnector,
+    tls: SslConnector,
+}
+
+impl Service<Uri> for HttpsConnector {
+    type Response = MaybeTlsStream;
+    type Error = Report;
+    type Future = Pin<Box<dyn Future<Output = Result<MaybeTlsStream>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.http.poll_ready(cx).map_err(Report::from)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connecting = self.http.call(uri.clone());
+        let tls = self.tls.clone();
+        Box::pin(async move {
+            let tcp = connecting.await?;
+            if uri.scheme() != Some(&Scheme::HTTPS) {
+                return Ok(MaybeTlsStream::Plain(tcp));
+            }
+            let host = uri.host().ok_or_else(|| eyre!("No host in {}", uri))?;
+            let ssl = tls.configure()?.into_ssl(host)?;
+            let mut stream = SslStream::new(ssl, tcp)?;
+            Pin::new(&mut stream).connect().await?;
+            Ok(MaybeTlsStream::Tls(stream))
+        })
+    }
+}
+
+enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(SslStream<TcpStream>),
+}
+
+impl Connection for MaybeTlsStream {
+    fn connected(&self) -> Connected {
+        match self {
+            Self::Plain(stream) => stream.connected(),
+            Self::Tls(stream) => stream.get_ref().connected(),
+        }
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
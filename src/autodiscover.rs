@@ -0,0 +1,196 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use eyre::Result;
+use hyper::{Body, Response, StatusCode, Uri};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tera::Context;
+use uuid::Uuid;
+
+use crate::{config::Domain, util::email_domain};
+
+/// Path of the Autodiscover v2 endpoint, the address can also be given as an additional
+/// path segment: `/autodiscover/autodiscover.json/v1.0/<email>?Protocol=...`
+pub const AUTODISCOVER_JSON_PATH: &str = "/autodiscover/autodiscover.json";
+
+/// Response schema of Outlook, the default if a POX request does not ask for one
+pub const OUTLOOK_RESPONSE_SCHEMA: &str =
+    "http://schemas.microsoft.com/exchange/autodiscover/outlook/responseschema/2006a";
+/// Response schema of Exchange ActiveSync clients (iOS, Android, Windows Mail)
+pub const MOBILESYNC_RESPONSE_SCHEMA: &str =
+    "http://schemas.microsoft.com/exchange/autodiscover/mobilesync/responseschema/2006";
+
+/// Error codes of POX Autodiscover error responses
+#[derive(Debug, Clone, Copy)]
+pub enum ErrorCode {
+    /// No configuration is available for the requested response schema
+    ProviderUnavailable = 601,
+}
+
+impl ErrorCode {
+    fn message(self) -> &'static str {
+        match self {
+            Self::ProviderUnavailable => "Provider could not be found for the requested schema",
+        }
+    }
+}
+
+/// Inserts what the `microsoft_error.xml` template needs to render `code` and returns its name
+pub fn insert_error(context: &mut Context, code: ErrorCode) -> &'static str {
+    let since_midnight = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+        % (24 * 60 * 60 * 1000);
+    context.insert(
+        "error_time",
+        &format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            since_midnight / 3_600_000,
+            since_midnight / 60_000 % 60,
+            since_midnight / 1000 % 60,
+            since_midnight % 1000
+        ),
+    );
+    context.insert("error_id", &(Uuid::new_v4().as_u128() as u32));
+    context.insert("error_code", &(code as u32));
+    context.insert("error_message", code.message());
+    "microsoft_error.xml"
+}
+
+/// The protocols that can be negotiated through the Autodiscover v2 endpoint
+#[derive(Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+enum AutodiscoverProtocol {
+    AutodiscoverV1,
+    ActiveSync,
+    Ews,
+}
+
+impl AutodiscoverProtocol {
+    const ALL: [Self; 3] = [Self::AutodiscoverV1, Self::ActiveSync, Self::Ews];
+
+    fn parse(protocol: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|p| format!("{:?}", p).eq_ignore_ascii_case(protocol))
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct RedirectResponse {
+    protocol: AutodiscoverProtocol,
+    url: String,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "PascalCase")]
+struct ErrorResponse {
+    error_code: &'static str,
+    error_message: String,
+}
+
+/// Whether `path` is the Autodiscover v2 endpoint, with or without the address appended
+pub fn is_autodiscover_json_path(path: &str) -> bool {
+    path.strip_prefix(AUTODISCOVER_JSON_PATH)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// The address of an Autodiscover v2 request, from the `email` parameter or the path
+pub fn autodiscover_json_email(uri: &Uri) -> Option<String> {
+    form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key.eq_ignore_ascii_case("email"))
+        .map(|(_, value)| value.into_owned())
+        .last()
+        .or_else(|| {
+            uri.path()
+                .get(AUTODISCOVER_JSON_PATH.len()..)
+                .and_then(|rest| rest.strip_prefix("/v1.0/"))
+                .filter(|email| !email.is_empty())
+                .map(|email| percent_decode_str(email).decode_utf8_lossy().into_owned())
+        })
+}
+
+/// Answers an Autodiscover v2 request (as sent by current Outlook versions before they fall back
+/// to the XML endpoint) with the URL of the requested protocol on `base_url`, or an error.
+pub fn autodiscover_json(uri: &Uri, base_url: &str, domain: &Domain) -> Result<Response<Body>> {
+    let protocol = form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+        .filter(|(key, _)| key.eq_ignore_ascii_case("protocol"))
+        .map(|(_, value)| value.into_owned())
+        .last();
+
+    let email = match autodiscover_json_email(uri) {
+        Some(email) => email,
+        None => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "InvalidUser",
+                "The email address is missing".to_owned(),
+            )
+        }
+    };
+    if email_domain(&email).as_ref() != Some(&domain.email_domain) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "InvalidUser",
+            format!(
+                "The email address '{}' does not belong to this server",
+                email
+            ),
+        );
+    }
+
+    let protocol_name = protocol.unwrap_or_default();
+    let protocol = match AutodiscoverProtocol::parse(&protocol_name) {
+        Some(protocol) => protocol,
+        None => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "InvalidProtocol",
+                format!(
+                    "The given protocol value '{}' is invalid. Supported values are 'AutodiscoverV1, ActiveSync, Ews'",
+                    protocol_name
+                ),
+            )
+        }
+    };
+
+    let url = match protocol {
+        AutodiscoverProtocol::AutodiscoverV1 => {
+            Some(format!("{}/autodiscover/autodiscover.xml", base_url))
+        }
+        AutodiscoverProtocol::ActiveSync => domain.activesync_url.clone(),
+        AutodiscoverProtocol::Ews => domain.ews_url.clone(),
+    };
+    match url {
+        Some(url) => {
+            let body = serde_json::to_string(&RedirectResponse { protocol, url })?;
+            Ok(Response::builder()
+                .header("Content-Type", "application/json")
+                .body(body.into())?)
+        }
+        None => error_response(
+            StatusCode::NOT_FOUND,
+            "ProtocolNotSupported",
+            format!(
+                "The protocol '{:?}' is not offered for this domain",
+                protocol
+            ),
+        ),
+    }
+}
+
+fn error_response(
+    status: StatusCode,
+    error_code: &'static str,
+    error_message: String,
+) -> Result<Response<Body>> {
+    let body = serde_json::to_string(&ErrorResponse {
+        error_code,
+        error_message,
+    })?;
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(body.into())?)
+}
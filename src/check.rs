@@ -0,0 +1,312 @@
+use std::{fmt, path::Path};
+
+use eyre::{eyre, Report, Result, WrapErr};
+use serde::Deserialize;
+use tokio::fs::read_to_string;
+use toml::Spanned;
+
+use crate::{
+    config::{one_or_many, Config, ConfigItem, SocketType},
+    global_state::{load_templates, Certs, KeyError},
+    host_map::overlapping_hosts,
+};
+
+/// Well-known ports of the server protocols, for plain/STARTTLS and for SSL connections
+type Ports = (&'static [u16], &'static [u16]);
+const SMTP_PORTS: Ports = (&[25, 587], &[465]);
+const IMAP_PORTS: Ports = (&[143], &[993]);
+const POP3_PORTS: Ports = (&[110], &[995]);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Error => write!(f, "error"),
+            Self::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+struct Problem {
+    severity: Severity,
+    /// Byte offset in the config file
+    offset: Option<usize>,
+    message: String,
+}
+
+/// Where the parts of the config that problems are reported for are in the file. Read leniently,
+/// as a subset of [`Config`].
+#[derive(Deserialize, Default)]
+struct ConfigSpans {
+    template_path: Option<Spanned<String>>,
+    socket_address: Option<Spanned<String>>,
+    tls_socket_address: Option<Spanned<String>>,
+    #[serde(default)]
+    listeners: Vec<ListenerSpans>,
+    #[serde(default)]
+    domains: Vec<DomainSpans>,
+}
+
+#[derive(Deserialize)]
+struct ListenerSpans {
+    address: Option<Spanned<String>>,
+    path: Option<Spanned<String>>,
+}
+
+#[derive(Deserialize)]
+struct DomainSpans {
+    email_domain: Option<Spanned<String>>,
+    ssl_chain: Option<Spanned<String>>,
+    ssl_key: Option<Spanned<String>>,
+    #[serde(default)]
+    allowed_hosts: Vec<Spanned<String>>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    smtp: Vec<ServerSpans>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    imap: Vec<ServerSpans>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pop3: Vec<ServerSpans>,
+}
+
+#[derive(Deserialize)]
+struct ServerSpans {
+    port: Option<Spanned<u16>>,
+}
+
+impl ConfigSpans {
+    /// Where the listener at `i` of [`Config::listeners`] is configured
+    fn listener(&self, i: usize) -> Option<usize> {
+        [&self.socket_address, &self.tls_socket_address]
+            .into_iter()
+            .flatten()
+            .map(Spanned::start)
+            .map(Some)
+            .chain(self.listeners.iter().map(|listener| {
+                (listener.address.as_ref())
+                    .or(listener.path.as_ref())
+                    .map(Spanned::start)
+            }))
+            .nth(i)
+            .flatten()
+    }
+
+    fn domain(&self, i: usize) -> Option<usize> {
+        let domain = self.domains.get(i)?;
+        domain.email_domain.as_ref().map(Spanned::start)
+    }
+
+    fn locate(&self, item: ConfigItem) -> Option<usize> {
+        match item {
+            ConfigItem::Config => None,
+            ConfigItem::Listener(i) => self.listener(i),
+            ConfigItem::Domain(i) => self.domain(i),
+            // Derived hosts are not in the file
+            ConfigItem::AllowedHost(i, j) => self
+                .domains
+                .get(i)
+                .and_then(|domain| domain.allowed_hosts.get(j))
+                .map(Spanned::start)
+                .or_else(|| self.domain(i)),
+        }
+    }
+}
+
+/// Loads the config at `config_path` like `run` would, without serving it, and prints all problems
+/// found. Returns whether it passed: it has no errors, and with `strict` no warnings either.
+pub async fn check(config_path: &Path, strict: bool) -> Result<bool> {
+    let contents = read_to_string(config_path)
+        .await
+        .wrap_err_with(|| format!("Cannot read {}", config_path.display()))?;
+    let problems = problems(&contents).await;
+    for problem in &problems {
+        print_problem(config_path, &contents, problem);
+    }
+    let errors = (problems.iter())
+        .filter(|p| p.severity == Severity::Error)
+        .count();
+    let warnings = problems.len() - errors;
+    println!(
+        "{}: {} error(s), {} warning(s)",
+        config_path.display(),
+        errors,
+        warnings
+    );
+    Ok(errors == 0 && (!strict || warnings == 0))
+}
+
+async fn problems(contents: &str) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut report = |severity, offset, err: Report| {
+        problems.push(Problem {
+            severity,
+            offset,
+            message: format!("{:#}", err),
+        })
+    };
+    let config = match Config::parse(contents) {
+        Ok(config) => config,
+        Err(err) => {
+            let offset = err
+                .downcast_ref::<toml::de::Error>()
+                .and_then(|err| err.line_col())
+                .map(|(line, col)| offset_of(contents, line, col));
+            report(Severity::Error, offset, err);
+            return problems;
+        }
+    };
+    // The config parsed, so this only fails on the unexpected
+    let spans: ConfigSpans = toml::from_str(contents).unwrap_or_default();
+
+    for (item, err) in config.problems() {
+        report(Severity::Error, spans.locate(item), err);
+    }
+    for (item, warning) in config.warnings() {
+        report(Severity::Warning, spans.locate(item), warning);
+    }
+    for (i, j, warning) in overlapping_hosts(&config.domains) {
+        let offset = spans.locate(ConfigItem::AllowedHost(i, j));
+        report(Severity::Warning, offset, warning);
+    }
+
+    for (i, domain) in config.domains.iter().enumerate() {
+        let domain_spans = spans.domains.get(i);
+        // Without both paths there is nothing to load, `Config::problems` reports why
+        if let (false, Some((chain_path, key_path))) =
+            (domain.acme_managed(), config.cert_paths(domain))
+        {
+            let span_of = |select: fn(&DomainSpans) -> &Option<Spanned<String>>| {
+                domain_spans
+                    .and_then(|d| select(d).as_ref())
+                    .map(Spanned::start)
+                    .or_else(|| spans.domain(i))
+            };
+            let chain_offset = span_of(|d| &d.ssl_chain);
+            let warnings = Certs::new(&chain_path, &key_path)
+                .await
+                .and_then(|certs| certs.warnings(domain));
+            match warnings {
+                Ok(warnings) => {
+                    for warning in warnings {
+                        let warning = eyre!("Domain {}: {}", domain.email_domain, warning);
+                        report(Severity::Warning, chain_offset, warning);
+                    }
+                }
+                Err(err) if err.downcast_ref::<KeyError>().is_some() => {
+                    let err = err.wrap_err(format!(
+                        "Domain {}: cannot use key {} for certificate {}",
+                        domain.email_domain,
+                        key_path.display(),
+                        chain_path.display()
+                    ));
+                    report(Severity::Error, span_of(|d| &d.ssl_key), err);
+                }
+                Err(err) => {
+                    let err = err.wrap_err(format!(
+                        "Domain {}: cannot load certificate {}",
+                        domain.email_domain,
+                        chain_path.display()
+                    ));
+                    report(Severity::Error, chain_offset, err);
+                }
+            }
+        }
+        let no_spans = vec![];
+        let server_spans = |select: fn(&DomainSpans) -> &Vec<ServerSpans>| {
+            domain_spans.map(select).unwrap_or(&no_spans)
+        };
+        for (protocol, servers, server_spans, (plain_ports, tls_ports)) in [
+            ("smtp", &domain.smtp, server_spans(|d| &d.smtp), SMTP_PORTS),
+            ("imap", &domain.imap, server_spans(|d| &d.imap), IMAP_PORTS),
+            ("pop3", &domain.pop3, server_spans(|d| &d.pop3), POP3_PORTS),
+        ] {
+            for (j, server) in servers.iter().enumerate() {
+                let expected = if server.socket_type == SocketType::SSL {
+                    plain_ports
+                        .contains(&server.port)
+                        .then_some("plain or STARTTLS")
+                } else {
+                    tls_ports.contains(&server.port).then_some("SSL")
+                };
+                if let Some(expected) = expected {
+                    let offset = (server_spans.get(j))
+                        .and_then(|s| s.port.as_ref())
+                        .map(Spanned::start)
+                        .or_else(|| spans.domain(i));
+                    report(
+                        Severity::Warning,
+                        offset,
+                        eyre!(
+                            "Domain {}: {} server {} uses {:?} on port {}, which is usually used with {}",
+                            domain.email_domain,
+                            protocol,
+                            server.host,
+                            server.socket_type,
+                            server.port,
+                            expected
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Err(err) = load_templates(&config).await {
+        let offset = spans.template_path.as_ref().map(Spanned::start);
+        report(Severity::Error, offset, err);
+    }
+    problems
+}
+
+/// Prints `problem` like a compiler diagnostic, `path:line:column: severity: message` followed by
+/// the line it is on
+fn print_problem(path: &Path, contents: &str, problem: &Problem) {
+    let offset = match problem.offset {
+        Some(offset) => offset,
+        None => {
+            println!(
+                "{}: {}: {}",
+                path.display(),
+                problem.severity,
+                problem.message
+            );
+            return;
+        }
+    };
+    let line_start = contents[..offset].rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let line_end = contents[offset..]
+        .find('\n')
+        .map(|i| offset + i)
+        .unwrap_or(contents.len());
+    let line = contents[..offset].matches('\n').count() + 1;
+    let column = contents[line_start..offset].chars().count() + 1;
+    println!(
+        "{}:{}:{}: {}: {}",
+        path.display(),
+        line,
+        column,
+        problem.severity,
+        problem.message
+    );
+    println!("    {}", &contents[line_start..line_end]);
+    println!("    {}^", " ".repeat(column - 1));
+}
+
+/// The byte offset of a zero-based line and column (in characters)
+fn offset_of(contents: &str, line: usize, column: usize) -> usize {
+    let line_start: usize = contents
+        .split_inclusive('\n')
+        .take(line)
+        .map(str::len)
+        .sum();
+    let line_start = line_start.min(contents.len());
+    contents[line_start..]
+        .char_indices()
+        .nth(column)
+        .map(|(i, _)| line_start + i)
+        .unwrap_or(contents.len())
+}
@@ -1,61 +1,1036 @@
 use std::{fmt, net::SocketAddr};
 
-use eyre::Result;
-use serde::{Deserialize, Serialize};
-use std::{fmt::Display, path::Path};
+use eyre::{ensure, eyre, Report, Result};
+use hyper::{StatusCode, Uri};
+use ipnet::IpNet;
+use openssl::sha::sha256;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
 use tokio::fs::read_to_string;
-use tracing::info;
+use tracing::{info, warn};
+
+use crate::{
+    acme,
+    host_map::{duplicate_hosts, is_pattern, validate_host, HostMap},
+    util::{email_domain, glob_match, normalize_domain},
+};
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct Config {
     pub domains: Vec<Domain>,
-    // [NOTE]: A change of this value after server start (with a reload) will have no effect!
-    pub socket_address: SocketAddr,
+    // Listeners are compared on reload, added ones are started and removed ones drained
+    /// Shorthand for a plain HTTP TCP listener
+    pub socket_address: Option<SocketAddr>,
+    /// Shorthand for an HTTPS TCP listener, certificates are selected by SNI
+    pub tls_socket_address: Option<SocketAddr>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
     pub template_path: String,
     pub watch_path: Option<String>,
+    /// Conventional hosts added to the `allowed_hosts` of every domain, unless it sets its own
+    #[serde(default)]
+    pub derive_hosts: DeriveHosts,
+    /// Hosts serving all domains, the domain is chosen by the email address in the request
+    #[serde(default)]
+    pub shared_hosts: Vec<String>,
+    /// What is done with requests for hosts that are not allowed for any domain
+    #[serde(default)]
+    pub unknown_host: UnknownHost,
+    /// Path the metrics are served on, e.g. `/metrics`, to the clients in `metrics_allowed`, on
+    /// every host
+    pub metrics_path: Option<String>,
+    /// Clients allowed to read the metrics, clients on Unix sockets always are
+    #[serde(default)]
+    pub metrics_allowed: Vec<IpNet>,
+    /// Reverse proxies whose `Forwarded` and `X-Forwarded-*` headers are honored
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNet>,
+    /// Certificate management for the domains without `ssl_chain`/`ssl_key`
+    pub acme: Option<AcmeConfig>,
 }
 
 impl Config {
     pub async fn load(config_path: impl AsRef<Path>) -> Result<Self> {
         info!("Loading config...");
         let contents = read_to_string(config_path).await?;
-        let config = toml::from_str(&contents)?;
+        let config = Self::parse(&contents)?;
+        config.validate()?;
+        for (_, warning) in config.warnings() {
+            warn!("{:#}", warning);
+        }
+        Ok(config)
+    }
+
+    /// Parses and normalizes a config without validating it
+    pub fn parse(contents: &str) -> Result<Self> {
+        let mut config: Self = toml::from_str(contents)?;
+        config.normalize();
+        config.derive_hosts();
         Ok(config)
     }
+
+    /// The domain serving unknown hosts, if there is one
+    pub fn unknown_host_domain(&self) -> Option<usize> {
+        match &self.unknown_host {
+            UnknownHost::Domain { domain } => {
+                self.domains.iter().position(|d| d.email_domain == *domain)
+            }
+            _ => None,
+        }
+    }
+
+    /// Normalizes all host and domain names, see [`normalize_domain`]. Invalid names are kept as
+    /// they are, [`Config::problems`] reports them.
+    fn normalize(&mut self) {
+        let normalize = |name: &mut String| {
+            if let Ok(normalized) = normalize_domain(name) {
+                *name = normalized;
+            }
+        };
+        for domain in &mut self.domains {
+            normalize(&mut domain.email_domain);
+            domain.email_domain_unicode = idna::domain_to_unicode(&domain.email_domain).0;
+            domain.allowed_hosts.iter_mut().for_each(normalize);
+        }
+        self.shared_hosts.iter_mut().for_each(normalize);
+        match &mut self.unknown_host {
+            UnknownHost::Domain { domain: host } | UnknownHost::Redirect { host } => {
+                normalize(host)
+            }
+            _ => {}
+        }
+    }
+
+    /// Adds the derived hosts of every domain to its `allowed_hosts`, after the explicit ones.
+    /// Domains with an invalid `email_domain` get none.
+    fn derive_hosts(&mut self) {
+        for domain in &mut self.domains {
+            if normalize_domain(&domain.email_domain).is_err() {
+                continue;
+            }
+            let derive_hosts = domain.derive_hosts.unwrap_or(self.derive_hosts);
+            for host in derive_hosts.hosts(domain) {
+                if !domain.allowed_hosts.contains(&host) {
+                    domain.allowed_hosts.push(host);
+                }
+            }
+        }
+    }
+
+    /// All configured listeners, including the `socket_address` shorthands
+    pub fn listeners(&self) -> Vec<ListenerConfig> {
+        let shorthands = [
+            (self.socket_address, ListenerProtocol::Http),
+            (self.tls_socket_address, ListenerProtocol::Https),
+        ];
+        shorthands
+            .into_iter()
+            .filter_map(|(address, protocol)| {
+                address.map(|address| ListenerConfig::new(ListenerBind::Tcp { address }, protocol))
+            })
+            .chain(self.listeners.iter().cloned())
+            .collect()
+    }
+
+    fn validate(&self) -> Result<()> {
+        match self.problems().into_iter().next() {
+            Some((_, err)) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    /// All problems of the config, with the item they were found in
+    pub fn problems(&self) -> Vec<(ConfigItem, Report)> {
+        let mut problems = vec![];
+        let listeners = self.listeners();
+        for i in 0..listeners.len() {
+            if let Err(err) = validate_listener(&listeners, i) {
+                problems.push((ConfigItem::Listener(i), err));
+            }
+        }
+        for err in self.settings_problems() {
+            problems.push((ConfigItem::Config, err));
+        }
+        for (i, domain) in self.domains.iter().enumerate() {
+            for err in self.domain_problems(domain) {
+                problems.push((ConfigItem::Domain(i), err));
+            }
+            for (j, host) in domain.allowed_hosts.iter().enumerate() {
+                if let Err(err) = validate_host(host) {
+                    problems.push((ConfigItem::AllowedHost(i, j), err));
+                }
+            }
+        }
+        for (i, j, err) in duplicate_hosts(&self.domains) {
+            problems.push((ConfigItem::AllowedHost(i, j), err));
+        }
+        // Hosts can only be looked up once they are valid, which was checked above
+        if let Ok(host_map) = HostMap::quiet(&self.domains) {
+            if let UnknownHost::Redirect { host } = &self.unknown_host {
+                if host_map.get(host).is_none() {
+                    problems.push((
+                        ConfigItem::Config,
+                        eyre!(
+                            "unknown_host: the redirect host {} is not allowed for any domain, so requests to it would be redirected again",
+                            host
+                        ),
+                    ));
+                }
+            }
+            if problems.is_empty() {
+                if let Err(err) = self.check_redirect_loops(&host_map) {
+                    problems.push((ConfigItem::Config, err));
+                }
+            }
+        }
+        problems
+    }
+
+    fn settings_problems(&self) -> Vec<Report> {
+        let mut problems = vec![];
+        match &self.unknown_host {
+            UnknownHost::Domain { domain }
+                if !self.domains.iter().any(|d| d.email_domain == *domain) =>
+            {
+                problems.push(eyre!("unknown_host: there is no domain {}", domain))
+            }
+            UnknownHost::Redirect { host } => {
+                if let Err(err) = normalize_domain(host) {
+                    problems.push(err.wrap_err("unknown_host: invalid redirect host"));
+                }
+            }
+            UnknownHost::ErrorPage { status, .. } if StatusCode::from_u16(*status).is_err() => {
+                problems.push(eyre!("unknown_host: invalid status {}", status))
+            }
+            _ => {}
+        }
+        for host in &self.shared_hosts {
+            if let Err(err) = normalize_domain(host) {
+                problems.push(err.wrap_err("Invalid shared host"));
+            } else if is_pattern(host) {
+                problems.push(eyre!("Shared host {} cannot be a pattern", host));
+            }
+        }
+        problems
+    }
+
+    fn domain_problems(&self, domain: &Domain) -> Vec<Report> {
+        let mut problems = vec![];
+        if let Err(err) = normalize_domain(&domain.email_domain) {
+            problems.push(err.wrap_err("Invalid email_domain"));
+        }
+        let mut check = |ok: bool, problem: Report| {
+            if !ok {
+                problems.push(problem);
+            }
+        };
+        check(
+            domain.ssl_chain.is_some() == domain.ssl_key.is_some(),
+            eyre!(
+                "Domain {}: ssl_chain and ssl_key have to be given together",
+                domain.email_domain
+            ),
+        );
+        check(
+            !domain.acme_managed() || self.acme.is_some(),
+            eyre!(
+                "Domain {} has no ssl_chain and ssl_key, so an [acme] section is needed",
+                domain.email_domain
+            ),
+        );
+        check(
+            !domain.acme_managed() || !domain.exact_hosts().is_empty(),
+            eyre!(
+                "Domain {}: ACME needs at least one allowed host that is no pattern to request a certificate for",
+                domain.email_domain
+            ),
+        );
+        check(
+            !domain.imap.is_empty() || !domain.pop3.is_empty(),
+            eyre!(
+                "Domain {} needs at least one incoming server (imap or pop3)",
+                domain.email_domain
+            ),
+        );
+        check(
+            !domain.smtp.is_empty(),
+            eyre!(
+                "Domain {} needs at least one outgoing server (smtp)",
+                domain.email_domain
+            ),
+        );
+        check(
+            domain.preferred_incoming != Protocol::Smtp,
+            eyre!(
+                "Domain {}: preferred_incoming has to be an incoming protocol (imap or pop3)",
+                domain.email_domain
+            ),
+        );
+        for template in domain
+            .username
+            .iter()
+            .chain(domain.all_servers().filter_map(|s| s.username.as_ref()))
+            .chain(
+                domain
+                    .caldav
+                    .iter()
+                    .chain(&domain.carddav)
+                    .filter_map(|dav| dav.username.as_ref()),
+            )
+            .chain(domain.redirects.iter().filter_map(|r| match &r.target {
+                RedirectTarget::RedirectAddr(addr) => Some(addr),
+                RedirectTarget::RedirectUrl(_) => None,
+            }))
+        {
+            let stripped = [
+                EMAIL_ADDRESS_PLACEHOLDER,
+                EMAIL_LOCAL_PART_PLACEHOLDER,
+                EMAIL_DOMAIN_PLACEHOLDER,
+            ]
+            .iter()
+            .fold(template.to_owned(), |t, placeholder| {
+                t.replace(placeholder, "")
+            });
+            check(
+                !stripped.contains('%'),
+                eyre!(
+                    "Domain {}: template {:?} contains an unknown placeholder",
+                    domain.email_domain,
+                    template
+                ),
+            );
+        }
+        for format in ClientFormat::ALL {
+            for (direction, _, usable) in domain.offered_servers(format) {
+                check(
+                    usable > 0,
+                    eyre!(
+                        "Domain {}: none of the {} servers uses an authentication method {:?} can express",
+                        domain.email_domain,
+                        direction,
+                        format
+                    ),
+                );
+            }
+        }
+        if let Some(mta_sts) = &domain.mta_sts {
+            check(
+                mta_sts.mode == MtaStsMode::None || !mta_sts.mx.is_empty(),
+                eyre!(
+                    "Domain {}: an MTA-STS policy needs at least one mx pattern",
+                    domain.email_domain
+                ),
+            );
+            check(
+                mta_sts.max_age <= MtaSts::MAX_MAX_AGE,
+                eyre!(
+                    "Domain {}: MTA-STS max_age can be at most {} seconds",
+                    domain.email_domain,
+                    MtaSts::MAX_MAX_AGE
+                ),
+            );
+        }
+        problems
+    }
+
+    /// Problems that do not keep the config from being served: servers that some client formats
+    /// leave out, as they cannot express their authentication method
+    pub fn warnings(&self) -> Vec<(ConfigItem, Report)> {
+        let mut warnings = vec![];
+        for (i, domain) in self.domains.iter().enumerate() {
+            for format in ClientFormat::ALL {
+                for (direction, all, usable) in domain.offered_servers(format) {
+                    if usable > 0 && usable < all {
+                        warnings.push((
+                            ConfigItem::Domain(i),
+                            eyre!(
+                                "Domain {}: {} of the {} servers will not be offered to {:?}, as it cannot express their authentication method",
+                                domain.email_domain,
+                                all - usable,
+                                direction,
+                                format
+                            ),
+                        ));
+                    }
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Paths of the certificate chain and key of `domain`, either configured or managed via ACME.
+    /// `None` if only one of `ssl_chain` and `ssl_key` is given, or neither without `[acme]`.
+    pub fn cert_paths(&self, domain: &Domain) -> Option<(PathBuf, PathBuf)> {
+        match (&domain.ssl_chain, &domain.ssl_key, &self.acme) {
+            (Some(chain), Some(key), _) => Some((chain.into(), key.into())),
+            (None, None, Some(acme)) => Some(acme::cert_paths(acme, domain)),
+            _ => None,
+        }
+    }
+
+    /// Follows the redirects of every rule for an address it matches, like Outlook would do, and
+    /// fails if that leads back to an address (and host) that was already visited.
+    /// This is a heuristic: only one sample address per rule is followed (see
+    /// [`Redirect::probe_address`]), so loops that only other addresses run into, e.g. because a
+    /// later domain has a more specific rule for them, are not detected.
+    fn check_redirect_loops(&self, host_map: &HostMap) -> Result<()> {
+        for domain in &self.domains {
+            for rule in &domain.redirects {
+                let probe = rule.probe_address(&domain.email_domain);
+                let mut domain = domain;
+                let mut email = probe.clone();
+                let mut visited = vec![];
+                loop {
+                    ensure!(
+                        !visited.contains(&(domain.email_domain.as_str(), email.clone())),
+                        "Redirect loop for address {} (of pattern {:?}): {}",
+                        probe,
+                        rule.address,
+                        visited
+                            .iter()
+                            .map(|(domain, email)| format!("{} ({})", email, domain))
+                            .collect::<Vec<_>>()
+                            .join(" -> ")
+                    );
+                    visited.push((domain.email_domain.as_str(), email.clone()));
+                    let next = match domain.redirect_for(&email) {
+                        // A new address starts the discovery over at the domain of that address
+                        Some(RedirectTarget::RedirectAddr(addr)) => {
+                            let addr_domain = email_domain(&addr);
+                            self.domains
+                                .iter()
+                                .find(|d| Some(&d.email_domain) == addr_domain.as_ref())
+                                .map(|d| (d, addr))
+                        }
+                        // A new URL keeps the address but asks the domain serving that host
+                        Some(RedirectTarget::RedirectUrl(url)) => {
+                            let host = url
+                                .parse::<Uri>()
+                                .ok()
+                                .and_then(|uri| uri.host().map(str::to_owned));
+                            host.and_then(|host| host_map.get(&host))
+                                .map(|i| (&self.domains[i], email.clone()))
+                        }
+                        None => None,
+                    };
+                    match next {
+                        Some((next_domain, next_email)) => {
+                            domain = next_domain;
+                            email = next_email;
+                        }
+                        None => break,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+pub struct ListenerConfig {
+    #[serde(flatten)]
+    pub bind: ListenerBind,
+    #[serde(default)]
+    pub protocol: ListenerProtocol,
+    /// Read the client address from a PROXY protocol (v1 or v2) header sent by a load balancer
+    #[serde(default)]
+    pub proxy_protocol: bool,
+    /// Peers whose PROXY protocol header is trusted, connections of others are served directly
+    #[serde(default)]
+    pub proxy_protocol_trusted: Vec<IpNet>,
+}
+
+impl ListenerConfig {
+    pub fn new(bind: ListenerBind, protocol: ListenerProtocol) -> Self {
+        Self {
+            bind,
+            protocol,
+            proxy_protocol: false,
+            proxy_protocol_trusted: vec![],
+        }
+    }
+}
+
+/// Where a listener accepts connections
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone)]
+#[serde(untagged)]
+pub enum ListenerBind {
+    Tcp {
+        address: SocketAddr,
+    },
+    Unix {
+        path: PathBuf,
+        /// Permissions of the socket file, e.g. `0o660`
+        mode: Option<u32>,
+        /// User and group owning the socket file, by name or id
+        owner: Option<String>,
+        group: Option<String>,
+    },
+}
+
+impl Display for ListenerBind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp { address } => write!(f, "{}", address),
+            Self::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ListenerProtocol {
+    #[default]
+    Http,
+    /// TLS with the certificate selected by SNI
+    Https,
+}
+
+/// What a problem found by [`Config::problems`] concerns
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigItem {
+    /// The config as a whole or one of its top-level settings
+    Config,
+    /// An entry of [`Config::listeners`]
+    Listener(usize),
+    Domain(usize),
+    /// An entry of the `allowed_hosts` of a domain
+    AllowedHost(usize, usize),
+}
+
+fn validate_listener(listeners: &[ListenerConfig], i: usize) -> Result<()> {
+    let listener = &listeners[i];
+    ensure!(
+        !listener.proxy_protocol
+            || !listener.proxy_protocol_trusted.is_empty()
+            || matches!(listener.bind, ListenerBind::Unix { .. }),
+        "Listener {}: proxy_protocol needs the proxies in proxy_protocol_trusted",
+        listener.bind
+    );
+    ensure!(
+        !listeners[..i]
+            .iter()
+            .any(|l| l.bind.to_string() == listener.bind.to_string()),
+        "Listener {} is configured more than once",
+        listener.bind
+    );
+    Ok(())
+}
+
+/// What is done with a request for a host that is not allowed for any domain
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Default)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum UnknownHost {
+    /// Answer with `403 Forbidden`
+    #[default]
+    Forbidden,
+    /// Serve the domain with this `email_domain`
+    Domain { domain: String },
+    /// Redirect to the same path on this host, which has to be allowed for a domain
+    Redirect { host: String },
+    /// Answer with this template, rendered with the requested `host`
+    ErrorPage {
+        #[serde(default = "default_unknown_host_template")]
+        template: String,
+        #[serde(default = "default_unknown_host_status")]
+        status: u16,
+    },
+    /// Close the connection without an answer
+    Close,
+}
+
+fn default_unknown_host_template() -> String {
+    "unknown_host.html".to_owned()
+}
+
+fn default_unknown_host_status() -> u16 {
+    404
+}
+
+/// Which conventional hosts of a domain are allowed without listing them in `allowed_hosts`
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DeriveHosts {
+    #[default]
+    None,
+    /// `autoconfig.<email_domain>`, `autodiscover.<email_domain>` and, with an MTA-STS policy,
+    /// `mta-sts.<email_domain>`
+    Discovery,
+    /// The discovery hosts and `<email_domain>` itself
+    All,
+}
+
+impl DeriveHosts {
+    fn hosts(self, domain: &Domain) -> Vec<String> {
+        if self == Self::None {
+            return vec![];
+        }
+        let mut hosts = vec![
+            format!("autoconfig.{}", domain.email_domain),
+            format!("autodiscover.{}", domain.email_domain),
+        ];
+        if domain.mta_sts.is_some() {
+            hosts.push(format!("mta-sts.{}", domain.email_domain));
+        }
+        if self == Self::All {
+            hosts.push(domain.email_domain.to_owned());
+        }
+        hosts
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct AcmeConfig {
+    /// Directory of the ACME CA, defaults to Let's Encrypt
+    #[serde(default = "default_acme_directory_url")]
+    pub directory_url: String,
+    /// Additional root certificate (PEM) to trust for the directory, e.g. the one of Pebble
+    pub directory_ca: Option<String>,
+    /// Contact addresses of the ACME account
+    #[serde(default)]
+    pub contact: Vec<String>,
+    /// Directory for the account key and the certificates, stored as
+    /// `<email_domain>/current/chain.pem` and `<email_domain>/current/key.pem`
+    pub storage_path: String,
+    #[serde(default)]
+    pub challenge: AcmeChallengeType,
+    /// Certificates are renewed this many days before they expire
+    #[serde(default = "default_acme_renew_before_days")]
+    pub renew_before_days: u32,
+}
+
+fn default_acme_directory_url() -> String {
+    "https://acme-v02.api.letsencrypt.org/directory".to_owned()
+}
+
+fn default_acme_renew_before_days() -> u32 {
+    30
+}
+
+/// How the control over the hosts is proven, both are answered by this server
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub enum AcmeChallengeType {
+    /// Via `/.well-known/acme-challenge/` on port 80
+    #[default]
+    #[serde(rename = "http-01")]
+    Http01,
+    /// Via the `acme-tls/1` protocol on port 443
+    #[serde(rename = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+impl AcmeChallengeType {
+    /// The name of the challenge type in ACME
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Http01 => "http-01",
+            Self::TlsAlpn01 => "tls-alpn-01",
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
 pub struct Domain {
+    /// Normalized to lowercase A-labels when the config is loaded
     pub email_domain: String,
-    pub ssl_chain: String,
-    pub ssl_key: String,
+    /// `email_domain` with U-labels, for display
+    #[serde(skip_deserializing)]
+    pub email_domain_unicode: String,
+    /// Certificate chain and key, if both are missing the certificate is managed via ACME
+    pub ssl_chain: Option<String>,
+    pub ssl_key: Option<String>,
     pub display_name: String,
     pub display_short_name: String,
+    #[serde(default)]
     pub allowed_hosts: Vec<String>,
-    pub smtp: ServerConfig,
-    pub imap: ServerConfig,
+    /// Overrides the global `derive_hosts` for this domain
+    pub derive_hosts: Option<DeriveHosts>,
+    // All server lists are ordered by priority, the first entry is the preferred one
+    #[serde(deserialize_with = "one_or_many")]
+    pub smtp: Vec<ServerConfig>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub imap: Vec<ServerConfig>,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub pop3: Vec<ServerConfig>,
+    /// Which incoming protocol clients should prefer if both are configured
+    #[serde(default)]
+    pub preferred_incoming: Protocol,
+    /// Login name template for all servers of this domain, see [`expand_username`]
+    pub username: Option<String>,
+    /// Exchange ActiveSync endpoint handed out via Autodiscover
+    pub activesync_url: Option<String>,
+    /// Exchange Web Services endpoint handed out via Autodiscover
+    pub ews_url: Option<String>,
+    /// Autodiscover redirects, the first rule matching an address is used
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+    /// Calendar server added to generated Apple profiles
+    pub caldav: Option<DavServer>,
+    /// Contacts server added to generated Apple profiles
+    pub carddav: Option<DavServer>,
+    /// MTA-STS policy served at `/.well-known/mta-sts.txt` on `mta-sts.<email_domain>`
+    pub mta_sts: Option<MtaSts>,
+}
+
+impl Domain {
+    /// Whether the certificate of this domain is requested and renewed via ACME
+    pub fn acme_managed(&self) -> bool {
+        self.ssl_chain.is_none() && self.ssl_key.is_none()
+    }
+
+    /// The `allowed_hosts` that are no patterns, which a certificate can be requested for
+    pub fn exact_hosts(&self) -> Vec<String> {
+        self.allowed_hosts
+            .iter()
+            .filter(|host| !is_pattern(host))
+            .cloned()
+            .collect()
+    }
+
+    /// How many of the incoming and outgoing servers there are and how many of them `format` can
+    /// offer
+    fn offered_servers(&self, format: ClientFormat) -> [(&'static str, usize, usize); 2] {
+        [
+            (
+                "incoming",
+                self.imap.len() + self.pop3.len(),
+                self.incoming_servers(format).len(),
+            ),
+            (
+                "outgoing",
+                self.smtp.len(),
+                self.outgoing_servers(format).len(),
+            ),
+        ]
+    }
+
+    /// All incoming servers usable by `format` by priority, the preferred protocol first
+    pub fn incoming_servers(&self, format: ClientFormat) -> Vec<ServerEntry<'_>> {
+        let mut servers: Vec<ServerEntry> = ServerEntry::list(self, Protocol::Imap, &self.imap)
+            .chain(ServerEntry::list(self, Protocol::Pop3, &self.pop3))
+            .filter(|s| s.server.authentication.supported_by(format))
+            .collect();
+        // stable sort, so the order within a protocol is kept
+        servers.sort_by_key(|s| s.protocol != self.preferred_incoming);
+        servers
+    }
+
+    /// The Autodiscover redirect of the first rule matching `email`, with its placeholders expanded
+    pub fn redirect_for(&self, email: &str) -> Option<RedirectTarget> {
+        self.redirects
+            .iter()
+            .find(|rule| rule.matches(email))
+            .map(|rule| match &rule.target {
+                RedirectTarget::RedirectAddr(addr) => {
+                    RedirectTarget::RedirectAddr(expand_username(addr, email))
+                }
+                RedirectTarget::RedirectUrl(url) => RedirectTarget::RedirectUrl(url.to_owned()),
+            })
+    }
+
+    /// The effective, unexpanded login name template of a CalDAV/CardDAV server
+    pub fn dav_username<'a>(&'a self, dav: &'a DavServer) -> &'a str {
+        dav.username
+            .as_deref()
+            .or(self.username.as_deref())
+            .unwrap_or(EMAIL_ADDRESS_PLACEHOLDER)
+    }
+
+    fn all_servers(&self) -> impl Iterator<Item = &ServerConfig> {
+        self.imap.iter().chain(&self.pop3).chain(&self.smtp)
+    }
+
+    /// All outgoing servers usable by `format` by priority
+    pub fn outgoing_servers(&self, format: ClientFormat) -> Vec<ServerEntry<'_>> {
+        ServerEntry::list(self, Protocol::Smtp, &self.smtp)
+            .filter(|s| s.server.authentication.supported_by(format))
+            .collect()
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub struct ServerConfig {
-    host: String,
-    port: u16,
-    socket_type: SocketType,
+pub struct Redirect {
+    /// Addresses this rule applies to, `*` matches any characters.
+    /// Without an `@` the pattern is matched against the local part only.
+    #[serde(default = "default_redirect_address")]
+    pub address: String,
+    #[serde(flatten)]
+    pub target: RedirectTarget,
+}
+
+fn default_redirect_address() -> String {
+    "*".to_owned()
+}
+
+impl Redirect {
+    fn matches(&self, email: &str) -> bool {
+        let email = email.to_lowercase();
+        let pattern = self.address.to_lowercase();
+        if pattern.contains('@') {
+            glob_match(&pattern, &email)
+        } else {
+            let local_part = email.rsplit_once('@').map(|(l, _)| l).unwrap_or(&email);
+            glob_match(&pattern, local_part)
+        }
+    }
+
+    /// A sample address of `email_domain` matched by this rule, with every `*` of the pattern
+    /// standing for `probe`
+    fn probe_address(&self, email_domain: &str) -> String {
+        let probe = self.address.replace('*', "probe");
+        if probe.contains('@') {
+            probe
+        } else {
+            format!("{}@{}", probe, email_domain)
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, PartialEq, Debug)]
-enum SocketType {
-    Plain,
-    SSL,
-    StartTLS,
+pub struct DavServer {
+    pub host: String,
+    #[serde(default = "default_dav_port")]
+    pub port: u16,
+    #[serde(default = "default_dav_use_ssl")]
+    pub use_ssl: bool,
+    /// URL of the principal, otherwise the client discovers it via `/.well-known/`
+    pub principal_url: Option<String>,
+    /// Login name template, defaults to the one of the domain
+    pub username: Option<String>,
+}
+
+fn default_dav_port() -> u16 {
+    443
+}
+
+fn default_dav_use_ssl() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct MtaSts {
+    pub mode: MtaStsMode,
+    /// Patterns of the allowed MX hosts, e.g. `mail.example.com` or `*.example.net`
+    pub mx: Vec<String>,
+    /// How long senders may cache the policy, in seconds
+    #[serde(default = "default_mta_sts_max_age")]
+    pub max_age: u32,
+}
+
+fn default_mta_sts_max_age() -> u32 {
+    // one week
+    604800
+}
+
+impl MtaSts {
+    /// Upper limit of `max_age` given by RFC 8461
+    const MAX_MAX_AGE: u32 = 31557600;
+
+    /// The policy file as served at `/.well-known/mta-sts.txt`
+    pub fn policy(&self) -> String {
+        let mut policy = format!("version: STSv1\r\nmode: {}\r\n", self.mode);
+        for mx in &self.mx {
+            policy.push_str(&format!("mx: {}\r\n", mx));
+        }
+        policy.push_str(&format!("max_age: {}\r\n", self.max_age));
+        policy
+    }
+
+    /// The `id` for the `_mta-sts` TXT record, derived from the policy so that it changes
+    /// whenever the policy does
+    pub fn id(&self) -> String {
+        let hash = sha256(self.policy().as_bytes());
+        // ids are limited to 32 alphanumeric characters
+        hash[..16].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum MtaStsMode {
+    Enforce,
+    Testing,
+    None,
 }
 
-impl Display for SocketType {
+impl Display for MtaStsMode {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            Self::Plain => write!(f, "plain"),
-            Self::SSL => write!(f, "SSL"),
-            Self::StartTLS => write!(f, "STARTTLS"),
+            Self::Enforce => write!(f, "enforce"),
+            Self::Testing => write!(f, "testing"),
+            Self::None => write!(f, "none"),
+        }
+    }
+}
+
+/// Where an Autodiscover client is sent to instead of getting settings: either another address
+/// to start the discovery over with (may contain the login name placeholders), or another
+/// Autodiscover URL to ask for the same address
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RedirectTarget {
+    RedirectAddr(String),
+    RedirectUrl(String),
+}
+
+/// The configuration formats of the different mail clients we serve
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ClientFormat {
+    Thunderbird,
+    Autodiscover,
+    Apple,
+}
+
+impl ClientFormat {
+    const ALL: [Self; 3] = [Self::Thunderbird, Self::Autodiscover, Self::Apple];
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Imap,
+    Pop3,
+    Smtp,
+}
+
+/// A server together with its protocol, as handed to the templates
+#[derive(Serialize, Debug)]
+pub struct ServerEntry<'a> {
+    pub protocol: Protocol,
+    /// The effective, unexpanded login name template of this server
+    pub username: &'a str,
+    #[serde(flatten)]
+    pub server: &'a ServerConfig,
+}
+
+impl<'a> ServerEntry<'a> {
+    fn list(
+        domain: &'a Domain,
+        protocol: Protocol,
+        servers: &'a [ServerConfig],
+    ) -> impl Iterator<Item = ServerEntry<'a>> {
+        servers.iter().map(move |server| ServerEntry {
+            protocol,
+            username: server
+                .username
+                .as_deref()
+                .or(domain.username.as_deref())
+                .unwrap_or(EMAIL_ADDRESS_PLACEHOLDER),
+            server,
+        })
+    }
+}
+
+const EMAIL_ADDRESS_PLACEHOLDER: &str = "%EMAILADDRESS%";
+const EMAIL_LOCAL_PART_PLACEHOLDER: &str = "%EMAILLOCALPART%";
+const EMAIL_DOMAIN_PLACEHOLDER: &str = "%EMAILDOMAIN%";
+
+/// Expands the placeholders of a login name template (the same ones Thunderbird understands)
+/// for `email`: `%EMAILADDRESS%` is the full address, `%EMAILLOCALPART%` the part before
+/// and `%EMAILDOMAIN%` the part after the `@`.
+pub fn expand_username(template: &str, email: &str) -> String {
+    let (local_part, domain) = email.rsplit_once('@').unwrap_or((email, ""));
+    template
+        .replace(EMAIL_ADDRESS_PLACEHOLDER, email)
+        .replace(EMAIL_LOCAL_PART_PLACEHOLDER, local_part)
+        .replace(EMAIL_DOMAIN_PLACEHOLDER, domain)
+}
+
+/// Allows a server to be given either as a single table or as an array of tables. Unlike an
+/// untagged enum, this keeps the spans of the values and reports errors at the offending field.
+pub fn one_or_many<'de, D, T>(deserializer: D) -> std::result::Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct OneOrMany<T>(std::marker::PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> serde::de::Visitor<'de> for OneOrMany<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "a table or an array of tables")
+        }
+
+        fn visit_map<A>(self, map: A) -> std::result::Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>,
+        {
+            T::deserialize(serde::de::value::MapAccessDeserializer::new(map)).map(|one| vec![one])
+        }
+
+        fn visit_seq<A>(self, seq: A) -> std::result::Result<Self::Value, A::Error>
+        where
+            A: serde::de::SeqAccess<'de>,
+        {
+            Vec::deserialize(serde::de::value::SeqAccessDeserializer::new(seq))
         }
     }
+
+    deserializer.deserialize_any(OneOrMany(std::marker::PhantomData))
+}
+
+#[derive(Deserialize, Serialize, PartialEq, Debug)]
+pub struct ServerConfig {
+    pub host: String,
+    pub port: u16,
+    pub socket_type: SocketType,
+    #[serde(default)]
+    authentication: Authentication,
+    /// Overrides the login name template of the domain for this server
+    // Templates get the effective value via `ServerEntry::username`
+    #[serde(skip_serializing)]
+    username: Option<String>,
+}
+
+// Serialized names match the Thunderbird vocabulary, the other formats are mapped in the templates
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy, Default)]
+enum Authentication {
+    #[default]
+    #[serde(rename(serialize = "password-cleartext"))]
+    PasswordCleartext,
+    /// CRAM-MD5 or similar challenge-response mechanisms
+    #[serde(rename(serialize = "password-encrypted"))]
+    PasswordEncrypted,
+    OAuth2,
+    #[serde(rename(serialize = "TLS-client-cert"))]
+    ClientCertificate,
+    GSSAPI,
+    NTLM,
+    #[serde(rename(serialize = "none"))]
+    None,
+}
+
+impl Authentication {
+    /// Whether the configuration format of a client has a way to express this method
+    fn supported_by(self, format: ClientFormat) -> bool {
+        match format {
+            ClientFormat::Thunderbird => true,
+            // Autodiscover only knows SPA (which is NTLM) on or off and whether auth is required
+            ClientFormat::Autodiscover => {
+                matches!(self, Self::PasswordCleartext | Self::NTLM | Self::None)
+            }
+            ClientFormat::Apple => matches!(
+                self,
+                Self::PasswordCleartext | Self::PasswordEncrypted | Self::NTLM | Self::None
+            ),
+        }
+    }
+}
+
+// Serialized names match the Thunderbird vocabulary as the templates compare against these
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Deserialize, Serialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum SocketType {
+    #[serde(rename(serialize = "plain"))]
+    Plain,
+    SSL,
+    #[serde(rename(serialize = "STARTTLS"))]
+    StartTLS,
 }
@@ -0,0 +1,232 @@
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+};
+
+use clap::ArgEnum;
+use eyre::Result;
+use serde::Serialize;
+
+use crate::config::{ClientFormat, Config, Domain, Protocol, SocketType};
+
+/// Output formats of the DNS record export
+#[derive(ArgEnum, Clone, Copy, Debug)]
+pub enum DnsFormat {
+    /// Fragments to paste into a BIND zone file
+    Bind,
+    /// A script for `nsupdate`
+    Nsupdate,
+    /// A JSON array of records
+    Json,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Record {
+    pub name: String,
+    pub ttl: u32,
+    #[serde(flatten)]
+    pub data: RecordData,
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Debug)]
+#[serde(tag = "type")]
+pub enum RecordData {
+    SRV {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: String,
+    },
+    CNAME {
+        target: String,
+    },
+    TXT {
+        text: String,
+    },
+}
+
+impl Display for Record {
+    /// A record in zone file presentation format
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} IN ", self.name, self.ttl)?;
+        match &self.data {
+            RecordData::SRV {
+                priority,
+                weight,
+                port,
+                target,
+            } => write!(f, "SRV {} {} {} {}", priority, weight, port, target),
+            RecordData::CNAME { target } => write!(f, "CNAME {}", target),
+            RecordData::TXT { text } => write!(f, "TXT {}", quote_txt(text)),
+        }
+    }
+}
+
+/// `text` as quoted character strings of a zone file (RFC 1035), split into strings of at most
+/// 255 bytes
+fn quote_txt(text: &str) -> String {
+    let strings: Vec<String> = text
+        .as_bytes()
+        .chunks(255)
+        .map(|chunk| {
+            let mut quoted = String::from('"');
+            for &byte in chunk {
+                match byte {
+                    b'"' | b'\\' => {
+                        quoted.push('\\');
+                        quoted.push(byte as char);
+                    }
+                    0x20..=0x7e => quoted.push(byte as char),
+                    _ => quoted.push_str(&format!("\\{:03}", byte)),
+                }
+            }
+            quoted.push('"');
+            quoted
+        })
+        .collect();
+    match strings.is_empty() {
+        true => "\"\"".to_owned(),
+        false => strings.join(" "),
+    }
+}
+
+/// Fully qualified form of `name`
+fn fqdn(name: &str) -> String {
+    if name.ends_with('.') {
+        name.to_owned()
+    } else {
+        format!("{}.", name)
+    }
+}
+
+/// All records needed for clients to discover the servers of `domain`, MTA-STS and TLS-RPT
+/// records are included if `mta_sts` is set or the domain has an MTA-STS policy.
+/// `target` is the host this server is reachable under, which the discovery hosts point to.
+pub fn domain_records(domain: &Domain, target: &str, ttl: u32, mta_sts: bool) -> Vec<Record> {
+    let mut records = vec![];
+    let record = |name: String, data| Record {
+        name: fqdn(&name),
+        ttl,
+        data,
+    };
+
+    // RFC 6186 (and RFC 8314 for implicit TLS submission), priority follows the configured order
+    // of the servers of each service
+    let servers = domain
+        .incoming_servers(ClientFormat::Thunderbird)
+        .into_iter()
+        .chain(domain.outgoing_servers(ClientFormat::Thunderbird));
+    let mut priorities: HashMap<&str, u16> = HashMap::new();
+    for entry in servers {
+        let implicit_tls = entry.server.socket_type == SocketType::SSL;
+        let service = match (entry.protocol, implicit_tls) {
+            (Protocol::Imap, false) => "_imap",
+            (Protocol::Imap, true) => "_imaps",
+            (Protocol::Pop3, false) => "_pop3",
+            (Protocol::Pop3, true) => "_pop3s",
+            (Protocol::Smtp, false) => "_submission",
+            (Protocol::Smtp, true) => "_submissions",
+        };
+        let priority = priorities.entry(service).or_default();
+        records.push(record(
+            format!("{}._tcp.{}", service, domain.email_domain),
+            RecordData::SRV {
+                priority: *priority,
+                weight: 1,
+                port: entry.server.port,
+                target: fqdn(&entry.server.host),
+            },
+        ));
+        *priority += 1;
+    }
+
+    records.push(record(
+        format!("_autodiscover._tcp.{}", domain.email_domain),
+        RecordData::SRV {
+            priority: 0,
+            weight: 1,
+            port: 443,
+            target: fqdn(target),
+        },
+    ));
+    for host in &domain.exact_hosts() {
+        if host.starts_with("autoconfig.") || host.starts_with("autodiscover.") {
+            records.push(record(
+                host.to_owned(),
+                RecordData::CNAME {
+                    target: fqdn(target),
+                },
+            ));
+        }
+    }
+
+    if mta_sts || domain.mta_sts.is_some() {
+        records.push(record(
+            format!("mta-sts.{}", domain.email_domain),
+            RecordData::CNAME {
+                target: fqdn(target),
+            },
+        ));
+        records.push(record(
+            format!("_mta-sts.{}", domain.email_domain),
+            RecordData::TXT {
+                text: format!(
+                    "v=STSv1; id={}",
+                    domain
+                        .mta_sts
+                        .as_ref()
+                        .map(|policy| policy.id())
+                        // Stub for domains without a configured policy, has to be filled in
+                        .unwrap_or_else(|| "CHANGEME".to_owned())
+                ),
+            },
+        ));
+        records.push(record(
+            format!("_smtp._tls.{}", domain.email_domain),
+            RecordData::TXT {
+                text: format!("v=TLSRPTv1; rua=mailto:tls-reports@{}", domain.email_domain),
+            },
+        ));
+    }
+    records
+}
+
+/// Renders the discovery records of all configured domains in `format`
+pub fn export(
+    config: &Config,
+    format: DnsFormat,
+    target: &str,
+    ttl: u32,
+    mta_sts: bool,
+) -> Result<String> {
+    let mut out = String::new();
+    match format {
+        DnsFormat::Bind => {
+            for domain in &config.domains {
+                out.push_str(&format!("; {}\n", domain.email_domain));
+                for record in domain_records(domain, target, ttl, mta_sts) {
+                    out.push_str(&format!("{}\n", record));
+                }
+            }
+        }
+        DnsFormat::Nsupdate => {
+            for domain in &config.domains {
+                for record in domain_records(domain, target, ttl, mta_sts) {
+                    out.push_str(&format!("update add {}\n", record));
+                }
+            }
+            out.push_str("send\n");
+        }
+        DnsFormat::Json => {
+            let records: Vec<Record> = config
+                .domains
+                .iter()
+                .flat_map(|domain| domain_records(domain, target, ttl, mta_sts))
+                .collect();
+            out = serde_json::to_string_pretty(&records)?;
+            out.push('\n');
+        }
+    }
+    Ok(out)
+}
@@ -0,0 +1,357 @@
+use std::net::{IpAddr, SocketAddr};
+
+use hyper::{header::HOST, HeaderMap};
+use ipnet::IpNet;
+use tracing::debug;
+
+use crate::{server::ClientAddr, util::normalize_domain};
+
+const X_FORWARDED_FOR: &str = "x-forwarded-for";
+const X_FORWARDED_HOST: &str = "x-forwarded-host";
+const X_FORWARDED_PROTO: &str = "x-forwarded-proto";
+
+/// Where a request originally came from and which host and scheme it was sent to, taken from the
+/// `Forwarded` or `X-Forwarded-*` headers if the peer is a trusted proxy
+#[derive(Debug)]
+pub struct RequestOrigin {
+    pub client: ClientAddr,
+    /// Normalized host name without port, `None` if the request did not name a valid one
+    pub host: Option<String>,
+    pub scheme: &'static str,
+}
+
+/// One hop of a proxy chain, as reported by the proxy that received it
+#[derive(Default)]
+struct Hop {
+    client: Option<ForwardedFor>,
+    host: Option<String>,
+    scheme: Option<&'static str>,
+}
+
+enum ForwardedFor {
+    Addr(SocketAddr),
+    /// `unknown` or an obfuscated identifier
+    Hidden,
+}
+
+impl RequestOrigin {
+    /// Resolves the origin of a request received from `peer`.
+    /// `Forwarded` takes precedence over `X-Forwarded-*`. The proxy chain is followed from the
+    /// peer backwards as long as the hops are trusted, the first untrusted hop is the client.
+    /// Connections on Unix sockets count as coming from a trusted proxy.
+    pub fn new(headers: &HeaderMap, peer: ClientAddr, trusted_proxies: &[IpNet]) -> Self {
+        let host = headers
+            .get(HOST)
+            .and_then(|host| std::str::from_utf8(host.as_bytes()).ok())
+            .and_then(request_host);
+        // Clients are expected to reach the server via HTTPS unless a proxy says otherwise
+        let mut origin = RequestOrigin {
+            client: peer,
+            host,
+            scheme: "https",
+        };
+        let is_trusted = |client: ClientAddr| match client.0 {
+            Some(addr) => trusted_proxies.iter().any(|net| net.contains(&addr.ip())),
+            None => true,
+        };
+        let hops = match forwarded_hops(headers).or_else(|| x_forwarded_hops(headers)) {
+            Some(hops) => hops,
+            None => return origin,
+        };
+        if trusted_proxies.is_empty() || !is_trusted(peer) {
+            debug!("Ignoring forwarding headers from untrusted peer {}", peer);
+            return origin;
+        }
+        for hop in hops.into_iter().rev() {
+            if let Some(host) = hop.host {
+                origin.host = Some(host);
+            }
+            if let Some(scheme) = hop.scheme {
+                origin.scheme = scheme;
+            }
+            match hop.client {
+                Some(ForwardedFor::Addr(addr)) => origin.client = ClientAddr(Some(addr)),
+                Some(ForwardedFor::Hidden) | None => break,
+            }
+            if !is_trusted(origin.client) {
+                break;
+            }
+        }
+        origin
+    }
+}
+
+/// The hops of the `Forwarded` header (RFC 7239), in the order they were added
+fn forwarded_hops(headers: &HeaderMap) -> Option<Vec<Hop>> {
+    let mut values = headers.get_all(hyper::header::FORWARDED).iter().peekable();
+    values.peek()?;
+    let mut hops = vec![];
+    for element in values
+        .filter_map(|value| std::str::from_utf8(value.as_bytes()).ok())
+        .flat_map(|value| value.split(','))
+    {
+        let mut hop = Hop::default();
+        for pair in element.split(';') {
+            let (key, value) = match pair.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim().trim_matches('"')),
+                None => continue,
+            };
+            if key.eq_ignore_ascii_case("for") {
+                hop.client = Some(parse_for(value));
+            } else if key.eq_ignore_ascii_case("host") {
+                hop.host = request_host(value);
+            } else if key.eq_ignore_ascii_case("proto") {
+                hop.scheme = parse_scheme(value);
+            }
+        }
+        hops.push(hop);
+    }
+    Some(hops)
+}
+
+/// The hops of `X-Forwarded-For`, with the host and scheme of `X-Forwarded-Host` and
+/// `X-Forwarded-Proto` attributed to the last one, which the peer added
+fn x_forwarded_hops(headers: &HeaderMap) -> Option<Vec<Hop>> {
+    let last_value = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| std::str::from_utf8(value.as_bytes()).ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .rfind(|value| !value.is_empty())
+    };
+    let mut hops: Vec<Hop> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| std::str::from_utf8(value.as_bytes()).ok())
+        .flat_map(|value| value.split(','))
+        .map(|client| Hop {
+            client: Some(parse_for(client.trim())),
+            ..Hop::default()
+        })
+        .collect();
+    let host = last_value(X_FORWARDED_HOST).and_then(request_host);
+    let scheme = last_value(X_FORWARDED_PROTO).and_then(parse_scheme);
+    if hops.is_empty() && host.is_none() && scheme.is_none() {
+        return None;
+    }
+    if hops.is_empty() {
+        hops.push(Hop::default());
+    }
+    let last = hops.last_mut().expect("at least one hop");
+    last.host = host;
+    last.scheme = scheme;
+    Some(hops)
+}
+
+/// Parses a node like `192.0.2.1`, `[2001:db8::1]:4711` or `unknown`
+fn parse_for(node: &str) -> ForwardedFor {
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return ForwardedFor::Addr(addr);
+    }
+    let ip = node
+        .strip_prefix('[')
+        .and_then(|node| node.strip_suffix(']'))
+        .unwrap_or(node);
+    match ip.parse::<IpAddr>() {
+        Ok(ip) => ForwardedFor::Addr(SocketAddr::new(ip, 0)),
+        Err(_) => ForwardedFor::Hidden,
+    }
+}
+
+fn parse_scheme(proto: &str) -> Option<&'static str> {
+    if proto.eq_ignore_ascii_case("https") {
+        Some("https")
+    } else if proto.eq_ignore_ascii_case("http") {
+        Some("http")
+    } else {
+        None
+    }
+}
+
+/// The normalized host of a `host[:port]` value, which may be given with U-labels. IPv6 literals
+/// like `[::1]` are only lowercased.
+fn request_host(host: &str) -> Option<String> {
+    let end = host.rfind(']').map(|i| i + 1).unwrap_or(0);
+    let host = match host[end..].find(':') {
+        Some(i) => &host[..end + i],
+        None => host,
+    };
+    match host.starts_with('[') {
+        true => Some(host.to_ascii_lowercase()),
+        false => normalize_domain(host).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header::{HeaderName, HeaderValue};
+
+    use super::*;
+
+    const PROXY: &str = "10.0.0.1:4711";
+
+    fn resolve(headers: &[(&str, &str)], peer: &str) -> RequestOrigin {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        RequestOrigin::new(&map, ClientAddr(Some(peer.parse().unwrap())), &trusted)
+    }
+
+    fn client(origin: &RequestOrigin) -> String {
+        origin.client.0.unwrap().to_string()
+    }
+
+    #[test]
+    fn forwarded() {
+        let origin = resolve(
+            &[
+                ("host", "backend.local"),
+                (
+                    "forwarded",
+                    "for=192.0.2.1;host=autoconfig.example.com;proto=http",
+                ),
+            ],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "192.0.2.1:0");
+        assert_eq!(origin.host.as_deref(), Some("autoconfig.example.com"));
+        assert_eq!(origin.scheme, "http");
+    }
+
+    #[test]
+    fn untrusted_peer() {
+        let origin = resolve(
+            &[
+                ("host", "autoconfig.example.com"),
+                ("forwarded", "for=192.0.2.1;host=other.example.com"),
+            ],
+            "192.0.2.2:4711",
+        );
+        assert_eq!(client(&origin), "192.0.2.2:4711");
+        assert_eq!(origin.host.as_deref(), Some("autoconfig.example.com"));
+        assert_eq!(origin.scheme, "https");
+    }
+
+    #[test]
+    fn walks_back_to_the_first_untrusted_hop() {
+        // The client spoofed the first entry, the second proxy is trusted
+        let origin = resolve(
+            &[(
+                "forwarded",
+                "for=198.51.100.1, for=192.0.2.1, for=10.0.0.2;host=autoconfig.example.com",
+            )],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "192.0.2.1:0");
+        assert_eq!(origin.host.as_deref(), Some("autoconfig.example.com"));
+    }
+
+    #[test]
+    fn hidden_hop_stops_the_walk() {
+        let origin = resolve(
+            &[("forwarded", "for=192.0.2.1, for=unknown, for=10.0.0.2")],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "10.0.0.2:0");
+        let origin = resolve(&[("forwarded", "for=_hidden")], PROXY);
+        assert_eq!(client(&origin), PROXY);
+    }
+
+    #[test]
+    fn quoted_and_ipv6_for() {
+        let origin = resolve(
+            &[("forwarded", r#"for="[2001:db8::1]:4711";proto=https"#)],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "[2001:db8::1]:4711");
+        let origin = resolve(&[("forwarded", r#"For="[2001:db8::1]""#)], PROXY);
+        assert_eq!(client(&origin), "[2001:db8::1]:0");
+    }
+
+    #[test]
+    fn multiple_forwarded_headers() {
+        let origin = resolve(
+            &[
+                ("forwarded", "for=192.0.2.1"),
+                ("forwarded", "for=10.0.0.2"),
+            ],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "192.0.2.1:0");
+    }
+
+    #[test]
+    fn x_forwarded_fallback() {
+        let origin = resolve(
+            &[
+                ("x-forwarded-for", "198.51.100.1, 192.0.2.1"),
+                ("x-forwarded-for", "10.0.0.2"),
+                ("x-forwarded-host", "autoconfig.example.com:8443"),
+                ("x-forwarded-proto", "http"),
+            ],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "192.0.2.1:0");
+        assert_eq!(origin.host.as_deref(), Some("autoconfig.example.com"));
+        assert_eq!(origin.scheme, "http");
+    }
+
+    #[test]
+    fn x_forwarded_host_without_for() {
+        let origin = resolve(&[("x-forwarded-host", "autoconfig.example.com")], PROXY);
+        assert_eq!(client(&origin), PROXY);
+        assert_eq!(origin.host.as_deref(), Some("autoconfig.example.com"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence() {
+        let origin = resolve(
+            &[
+                ("forwarded", "for=192.0.2.1"),
+                ("x-forwarded-for", "198.51.100.1"),
+            ],
+            PROXY,
+        );
+        assert_eq!(client(&origin), "192.0.2.1:0");
+    }
+
+    #[test]
+    fn unix_socket_peer_is_trusted() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::FORWARDED,
+            HeaderValue::from_static("for=192.0.2.1"),
+        );
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap()];
+        let origin = RequestOrigin::new(&headers, ClientAddr(None), &trusted);
+        assert_eq!(client(&origin), "192.0.2.1:0");
+    }
+
+    #[test]
+    fn request_host_strips_the_port() {
+        assert_eq!(
+            request_host("Autoconfig.Example.com:443").as_deref(),
+            Some("autoconfig.example.com")
+        );
+        assert_eq!(
+            request_host("autoconfig.example.com.").as_deref(),
+            Some("autoconfig.example.com")
+        );
+        assert_eq!(
+            request_host("[2001:DB8::1]:443").as_deref(),
+            Some("[2001:db8::1]")
+        );
+        assert_eq!(request_host("[::1]").as_deref(), Some("[::1]"));
+        assert_eq!(
+            request_host("bücher.example:80").as_deref(),
+            Some("xn--bcher-kva.example")
+        );
+    }
+}
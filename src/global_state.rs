@@ -1,26 +1,51 @@
-use crate::config::Config;
+use crate::{
+    acme::{self, AcmeChallenges},
+    config::{Config, Domain, UnknownHost},
+    host_map::HostMap,
+    metrics::Metrics,
+    server::{ListenerSocket, Listeners},
+    systemd,
+    util::{expand_username_filter, normalize_domain},
+};
 use arc_swap::{ArcSwap, Guard};
-use eyre::{ensure, Result};
+use eyre::{ensure, eyre, Result, WrapErr};
 use openssl::{
+    asn1::Asn1Time,
     pkey::{PKey, Private},
     stack::Stack,
-    x509::X509,
+    x509::{X509Ref, X509VerifyResult, X509},
 };
+use sd_notify::NotifyState;
 use std::{
     collections::HashMap,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use tera::Tera;
 use tokio::{
     signal::unix::{signal, SignalKind},
-    sync::mpsc::Receiver,
+    sync::{mpsc::Receiver, Mutex},
     task::spawn_blocking,
+    time::sleep,
 };
 use tracing::{error, info, instrument, warn};
 
+/// How often the certificates are checked for expiry
+const EXPIRY_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+/// Certificates expiring in fewer days are warned about
+const EXPIRY_WARNING_DAYS: i32 = 14;
+
 /// A simple wrapper for a global state that allows for reloading of the config via a unix signal
-pub struct GlobalState(ArcSwap<GlobalStateData>);
+pub struct GlobalState {
+    data: ArcSwap<GlobalStateData>,
+    /// Pending ACME challenges, kept outside of the data so they survive reloads
+    pub acme_challenges: AcmeChallenges,
+    /// Counters that survive reloads as well
+    pub metrics: Metrics,
+    /// The listeners serving this state, updated on every reload once started
+    listeners: Mutex<Option<Listeners>>,
+}
 
 #[derive(Debug)]
 pub enum Notify {
@@ -30,23 +55,59 @@ pub enum Notify {
 impl GlobalState {
     pub async fn new(config_path: PathBuf, notify: Option<Receiver<Notify>>) -> Result<Arc<Self>> {
         let initial_state = GlobalStateData::new(&config_path).await?;
-        let this = Arc::new(Self(ArcSwap::from_pointee(initial_state)));
+        let this = Arc::new(Self {
+            data: ArcSwap::from_pointee(initial_state),
+            acme_challenges: AcmeChallenges::default(),
+            metrics: Metrics::default(),
+            listeners: Mutex::new(None),
+        });
         this.clone().install_reload_handler(config_path, notify);
         Ok(this)
     }
 
+    /// Starts the configured listeners, which are kept up to date on reloads
+    pub async fn start_listeners(self: &Arc<Self>, mut listeners: Listeners) -> Result<()> {
+        let result = listeners.update(self.load().config.listeners(), self).await;
+        *self.listeners.lock().await = Some(listeners);
+        result
+    }
+
+    /// The sockets of the running listeners, to hand them over to another process
+    pub async fn listener_sockets(&self) -> Vec<ListenerSocket> {
+        match self.listeners.lock().await.as_ref() {
+            Some(listeners) => listeners.sockets(),
+            None => vec![],
+        }
+    }
+
+    /// Stops all listeners, they are no longer started on reloads
+    pub async fn stop_listeners(&self) {
+        if let Some(listeners) = self.listeners.lock().await.take() {
+            listeners.shutdown();
+        }
+    }
+
     #[instrument(skip(self))]
-    async fn reload_state(&self, config_path: &Path) {
+    async fn reload_state(self: &Arc<Self>, config_path: &Path) {
         info!("Reloading global state...");
+        systemd::notify_reloading();
         match GlobalStateData::new(config_path).await {
             Ok(new_state_data) => {
                 let new_state = Arc::new(new_state_data);
-                let old_state = self.0.swap(new_state.clone());
+                let old_state = self.data.swap(new_state.clone());
 
                 info!(
                     state_changed = new_state.as_ref().config != old_state.as_ref().config,
                     message = "Global state updated; succefully reloaded!"
                 );
+                systemd::notify(&[NotifyState::Status("Configuration reloaded")]);
+                // Reloaded certificates are checked right away instead of with the next check
+                new_state.check_expiry();
+                if let Some(listeners) = self.listeners.lock().await.as_mut() {
+                    if let Err(error) = listeners.update(new_state.config.listeners(), self).await {
+                        error!(%error, message = "Not all listeners could be updated");
+                    }
+                }
             }
             Err(error) => {
                 error!(
@@ -54,12 +115,29 @@ impl GlobalState {
                     %error,
                     message = "Global state not updated; error while reloading",
                 );
+                systemd::notify(&[NotifyState::Status(&format!("Reload failed: {:#}", error))]);
             }
         }
+        systemd::notify(&[NotifyState::Ready]);
     }
 
     pub fn load(&self) -> Guard<Arc<GlobalStateData>> {
-        self.0.load()
+        self.data.load()
+    }
+
+    /// The current state, for holding on to it longer than a request
+    pub fn load_full(&self) -> Arc<GlobalStateData> {
+        self.data.load_full()
+    }
+
+    /// Starts the task that warns about certificates that expire soon or have expired
+    pub fn spawn_expiry_check(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                self.load().check_expiry();
+                sleep(EXPIRY_CHECK_INTERVAL).await;
+            }
+        });
     }
 
     fn install_reload_handler(
@@ -107,52 +185,189 @@ pub struct Certs {
     pub key: PKey<Private>,
 }
 
+/// Context of the errors of [`Certs::new`] that are caused by the key rather than the chain
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid key")]
+pub struct KeyError;
+
 impl Certs {
-    async fn new(chain_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self> {
+    /// Loads a certificate chain, leaf first, and its key. Fails if the key does not belong to the
+    /// leaf or a certificate is not issued by the one after it.
+    pub async fn new(chain_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self> {
         let chain_buf = tokio::fs::read(chain_path).await?;
         let chain_stack = X509::stack_from_pem(&chain_buf)?;
         ensure!(
             !chain_stack.is_empty(),
             "At least one certificate has to be in the chain!"
         );
+        for (i, pair) in chain_stack.windows(2).enumerate() {
+            ensure!(
+                pair[1].issued(&pair[0]) == X509VerifyResult::OK
+                    && pair[0].verify(&*pair[1].public_key()?)?,
+                "Certificate {} of the chain is not issued by the one after it!",
+                i + 1
+            );
+        }
         let cert = chain_stack[0].clone();
         let mut chain = Stack::new()?;
         for cc in chain_stack {
             chain.push(cc)?;
         }
 
+        let key = Self::load_key(key_path, &cert).await.wrap_err(KeyError)?;
+        Ok(Self { cert, chain, key })
+    }
+
+    async fn load_key(key_path: impl AsRef<Path>, cert: &X509) -> Result<PKey<Private>> {
         let key_buf = tokio::fs::read(key_path).await?;
         let key = PKey::private_key_from_pem(&key_buf)?;
+        ensure!(
+            cert.public_key()?.public_eq(&key),
+            "The key does not belong to the certificate!"
+        );
+        Ok(key)
+    }
 
-        Ok(Self { cert, chain, key })
+    /// Problems that do not keep the certificates from being served, but clients of `domain` will
+    /// reject them: expired or not yet valid certificates and hosts the leaf does not cover
+    pub fn warnings(&self, domain: &Domain) -> Result<Vec<String>> {
+        let mut warnings = vec![];
+        let now = Asn1Time::days_from_now(0)?;
+        for (i, cert) in self.chain.iter().enumerate() {
+            if cert.not_after() < now {
+                warnings.push(format!(
+                    "certificate {} of the chain expired on {}",
+                    i + 1,
+                    cert.not_after()
+                ));
+            } else if cert.not_before() > now {
+                warnings.push(format!(
+                    "certificate {} of the chain is not valid before {}",
+                    i + 1,
+                    cert.not_before()
+                ));
+            }
+        }
+        let uncovered = uncovered_hosts(&self.cert, &domain.exact_hosts());
+        if !uncovered.is_empty() {
+            warnings.push(format!(
+                "the certificate does not cover {}",
+                uncovered.join(", ")
+            ));
+        }
+        Ok(warnings)
+    }
+
+    /// Whole days until the leaf certificate expires, negative once it has
+    pub fn days_until_expiry(&self) -> Result<i32> {
+        Ok(Asn1Time::days_from_now(0)?
+            .diff(self.cert.not_after())?
+            .days)
     }
 }
 
+/// The `hosts` that are not among the DNS names of `cert`, which may contain wildcards
+pub fn uncovered_hosts(cert: &X509Ref, hosts: &[String]) -> Vec<String> {
+    let names: Vec<String> = cert
+        .subject_alt_names()
+        .map(|names| {
+            names
+                .iter()
+                .filter_map(|name| name.dnsname().map(str::to_lowercase))
+                .collect()
+        })
+        .unwrap_or_default();
+    let covers = |name: &String, host: &str| match name.strip_prefix("*.") {
+        // A wildcard stands for exactly one label
+        Some(parent) => host
+            .split_once('.')
+            .is_some_and(|(_, host_parent)| host_parent == parent),
+        None => name == host,
+    };
+    hosts
+        .iter()
+        .filter(|host| !names.iter().any(|name| covers(name, &host.to_lowercase())))
+        .cloned()
+        .collect()
+}
+
 pub struct GlobalStateData {
     pub config: Config,
-    /// Mapping of allowed domain to index
-    pub host_map: HashMap<String, usize>,
+    /// Mapping of allowed hosts to the index of their domain
+    pub host_map: HostMap,
     pub cert_map: HashMap<String, Certs>,
 
     pub templates: Tera,
 }
 
 impl GlobalStateData {
+    /// The certificates for an SNI host name, or of the unknown host or first domain if there is no
+    /// (known) one
+    pub fn certs_for_server_name(&self, server_name: Option<&str>) -> Option<&Certs> {
+        let domain_idx = server_name
+            .and_then(|name| normalize_domain(name).ok())
+            .and_then(|name| self.host_map.get(&name))
+            .or_else(|| self.config.unknown_host_domain())
+            .unwrap_or(0);
+        let domain = self.config.domains.get(domain_idx)?;
+        self.cert_map.get(&domain.email_domain)
+    }
+
+    fn check_expiry(&self) {
+        for domain in &self.config.domains {
+            let days = match self
+                .cert_map
+                .get(&domain.email_domain)
+                .map(Certs::days_until_expiry)
+            {
+                Some(Ok(days)) => days,
+                Some(Err(err)) => {
+                    warn!(
+                        "Domain {}: cannot check certificate expiry: {:#}",
+                        domain.email_domain, err
+                    );
+                    continue;
+                }
+                None => continue,
+            };
+            if days < 0 {
+                error!(
+                    "Domain {}: the certificate has expired, clients reject it and the profiles signed with it",
+                    domain.email_domain
+                );
+            } else if days < EXPIRY_WARNING_DAYS {
+                warn!(
+                    "Domain {}: the certificate expires in {} days",
+                    domain.email_domain, days
+                );
+            }
+        }
+    }
+
     async fn new(config_path: &Path) -> Result<Self> {
         let config = Config::load(config_path).await?;
-        let mut host_map = HashMap::new();
+        let host_map = HostMap::new(&config.domains)?;
         let mut cert_map = HashMap::new();
-        for (i, domain) in config.domains.iter().enumerate() {
-            for allowed_host in &domain.allowed_hosts {
-                host_map.insert(allowed_host.to_owned(), i);
+        for domain in &config.domains {
+            // Rejected by `Config::load`
+            let (chain_path, key_path) = config.cert_paths(domain).ok_or_else(|| {
+                eyre!("Domain {}: no certificate configured", domain.email_domain)
+            })?;
+            if let (true, Some(acme)) = (domain.acme_managed(), &config.acme) {
+                acme::ensure_placeholder(acme, domain).await?;
             }
-            cert_map.insert(
-                domain.email_domain.to_owned(),
-                Certs::new(&domain.ssl_chain, &domain.ssl_key).await?,
-            );
+            let certs = Certs::new(&chain_path, &key_path)
+                .await
+                .map_err(|err| eyre!("Domain {}: {:#}", domain.email_domain, err))?;
+            // Managed certificates are taken care of by the ACME task
+            if !domain.acme_managed() {
+                for warning in certs.warnings(domain)? {
+                    warn!("Domain {}: {}", domain.email_domain, warning);
+                }
+            }
+            cert_map.insert(domain.email_domain.to_owned(), certs);
         }
-        let template_path = config.template_path.clone();
-        let templates = spawn_blocking(move || Tera::new(&template_path)).await??;
+        let templates = load_templates(&config).await?;
         Ok(Self {
             config,
             host_map,
@@ -161,3 +376,37 @@ impl GlobalStateData {
         })
     }
 }
+
+/// Templates rendered by `serve`
+const TEMPLATES: [&str; 6] = [
+    "apple_email.html",
+    "apple_config.plist",
+    "thunderbolt_config.xml",
+    "microsoft_config.xml",
+    "microsoft_mobilesync.xml",
+    "microsoft_error.xml",
+];
+
+/// Loads the templates at the `template_path` of `config`. Fails if one that is rendered for
+/// `config` is missing.
+pub async fn load_templates(config: &Config) -> Result<Tera> {
+    let template_path = config.template_path.clone();
+    let mut templates = spawn_blocking(move || Tera::new(&template_path))
+        .await?
+        .wrap_err_with(|| format!("Cannot load the templates at {:?}", config.template_path))?;
+    templates.register_filter("expand_username", expand_username_filter);
+    let error_page = match &config.unknown_host {
+        UnknownHost::ErrorPage { template, .. } => Some(template.as_str()),
+        _ => None,
+    };
+    let missing: Vec<&str> = (TEMPLATES.into_iter().chain(error_page))
+        .filter(|template| !templates.get_template_names().any(|name| name == *template))
+        .collect();
+    ensure!(
+        missing.is_empty(),
+        "template_path {:?} is missing the templates {}",
+        config.template_path,
+        missing.join(", ")
+    );
+    Ok(templates)
+}
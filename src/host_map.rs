@@ -0,0 +1,367 @@
+use std::collections::HashMap;
+
+use eyre::{ensure, eyre, Report, Result};
+use tracing::warn;
+
+use crate::{config::Domain, util::normalize_domain};
+
+/// Maps request and SNI host names to the index of the domain serving them.
+/// Exact `allowed_hosts` take precedence over patterns, of which the most specific one wins.
+pub struct HostMap {
+    exact: HashMap<String, usize>,
+    /// Ordered by precedence
+    patterns: Vec<(HostPattern, usize)>,
+}
+
+impl HostMap {
+    /// Fails if a host or pattern is allowed for more than one domain. Hosts of one domain that
+    /// a pattern of another domain also matches are logged as warnings, see [`overlapping_hosts`].
+    pub fn new(domains: &[Domain]) -> Result<Self> {
+        let host_map = Self::quiet(domains)?;
+        for (_, _, warning) in overlapping_hosts(domains) {
+            warn!("{:#}", warning);
+        }
+        Ok(host_map)
+    }
+
+    /// Like [`HostMap::new`], without logging the overlapping hosts, for checking a config
+    pub fn quiet(domains: &[Domain]) -> Result<Self> {
+        if let Some((_, _, err)) = duplicate_hosts(domains).into_iter().next() {
+            return Err(err);
+        }
+        let mut exact = HashMap::new();
+        let mut patterns = vec![];
+        for (i, domain) in domains.iter().enumerate() {
+            for host in &domain.allowed_hosts {
+                match HostPattern::parse(host)? {
+                    Some(pattern) => patterns.push((pattern, i)),
+                    None => {
+                        exact.insert(host.to_owned(), i);
+                    }
+                }
+            }
+        }
+        // Patterns with the same precedence cannot both match a host unless they are equal
+        patterns.sort_by_key(|(pattern, _)| std::cmp::Reverse(pattern.precedence()));
+        Ok(Self { exact, patterns })
+    }
+
+    pub fn get(&self, host: &str) -> Option<usize> {
+        self.exact.get(host).copied().or_else(|| {
+            self.patterns
+                .iter()
+                .find(|(pattern, _)| pattern.matches(host))
+                .map(|(_, i)| *i)
+        })
+    }
+}
+
+/// A host name with one `*` label, which matches one or more labels,
+/// e.g. `autoconfig.*` or `*.mail.example.net`
+#[derive(Debug)]
+struct HostPattern {
+    prefix: Vec<String>,
+    suffix: Vec<String>,
+}
+
+impl HostPattern {
+    /// The pattern in `host`, `None` if it is a plain host name
+    fn parse(host: &str) -> Result<Option<Self>> {
+        if !is_pattern(host) {
+            return Ok(None);
+        }
+        let labels: Vec<&str> = host.split('.').collect();
+        ensure!(
+            labels.iter().all(|label| !label.is_empty()),
+            "Host pattern {:?} has an empty label",
+            host
+        );
+        ensure!(
+            labels
+                .iter()
+                .all(|label| *label == "*" || !label.contains('*')),
+            "Host pattern {:?}: * can only stand for whole labels",
+            host
+        );
+        ensure!(
+            labels.iter().filter(|label| **label == "*").count() == 1,
+            "Host pattern {:?} can only contain one *",
+            host
+        );
+        ensure!(
+            labels.len() > 1,
+            "Host pattern {:?} would match every host",
+            host
+        );
+        let star = labels.iter().position(|label| *label == "*").unwrap();
+        Ok(Some(Self {
+            prefix: labels[..star].iter().map(|l| l.to_string()).collect(),
+            suffix: labels[star + 1..].iter().map(|l| l.to_string()).collect(),
+        }))
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let labels: Vec<&str> = host.split('.').collect();
+        labels.len() > self.prefix.len() + self.suffix.len()
+            && self.prefix.iter().zip(&labels).all(|(p, l)| p == l)
+            && (self.suffix.iter().rev())
+                .zip(labels.iter().rev())
+                .all(|(s, l)| s == l)
+    }
+
+    /// More fixed labels win, then the longer fixed suffix
+    fn precedence(&self) -> (usize, usize) {
+        (self.prefix.len() + self.suffix.len(), self.suffix.len())
+    }
+
+    /// Whether some host matches both patterns. Only host lengths up to the point where the
+    /// prefixes and suffixes of both no longer share a label need to be tried.
+    fn overlaps(&self, other: &Self) -> bool {
+        let fixed = |pattern: &Self| pattern.prefix.len() + pattern.suffix.len();
+        let shortest = fixed(self).max(fixed(other)) + 1;
+        let longest = self.prefix.len().max(other.prefix.len())
+            + self.suffix.len().max(other.suffix.len())
+            + 1;
+        (shortest..=longest).any(|length| {
+            let mut labels: Vec<Option<&str>> = vec![None; length];
+            for pattern in [self, other] {
+                let fixed_labels = (0..)
+                    .zip(&pattern.prefix)
+                    .chain((length - pattern.suffix.len()..).zip(&pattern.suffix));
+                for (i, label) in fixed_labels {
+                    match labels[i] {
+                        Some(other) if other != label => return false,
+                        _ => labels[i] = Some(label),
+                    }
+                }
+            }
+            true
+        })
+    }
+}
+
+/// Whether an `allowed_hosts` entry is a pattern rather than a host name
+pub fn is_pattern(host: &str) -> bool {
+    host.contains('*')
+}
+
+/// Checks that an `allowed_hosts` entry is a valid host name or pattern
+pub fn validate_host(host: &str) -> Result<()> {
+    normalize_domain(host)?;
+    HostPattern::parse(host).map(|_| ())
+}
+
+/// The hosts and patterns allowed for a domain that share hosts with a different entry of an earlier
+/// domain: hosts matched by a pattern and overlapping patterns, as the indices of the domain and of
+/// the entry in its `allowed_hosts`. Such hosts are served by the exact host or the more specific
+/// pattern, see [`HostMap::get`].
+pub fn overlapping_hosts(domains: &[Domain]) -> Vec<(usize, usize, Report)> {
+    let mut earlier: Vec<(&str, Option<HostPattern>, usize)> = vec![];
+    let mut overlaps = vec![];
+    for (i, domain) in domains.iter().enumerate() {
+        let entries: Vec<_> = (domain.allowed_hosts.iter())
+            .map(|host| (host.as_str(), HostPattern::parse(host).ok().flatten(), i))
+            .collect();
+        for (j, (host, pattern, _)) in entries.iter().enumerate() {
+            for (other_host, other_pattern, other) in &earlier {
+                // Equal entries are duplicates, see `duplicate_hosts`
+                if other_host == host {
+                    continue;
+                }
+                let other_domain = &domains[*other].email_domain;
+                let overlap = match (pattern, other_pattern) {
+                    (Some(pattern), Some(other_pattern)) if pattern.overlaps(other_pattern) => {
+                        let winner = if pattern.precedence() > other_pattern.precedence() {
+                            &domain.email_domain
+                        } else {
+                            other_domain
+                        };
+                        eyre!(
+                            "Host pattern {} of {} overlaps with {} of {}, hosts matching both are served by {}",
+                            host,
+                            domain.email_domain,
+                            other_host,
+                            other_domain,
+                            winner
+                        )
+                    }
+                    (None, Some(other_pattern)) if other_pattern.matches(host) => eyre!(
+                        "Host {} of {} is also matched by the pattern {} of {}",
+                        host,
+                        domain.email_domain,
+                        other_host,
+                        other_domain
+                    ),
+                    (Some(pattern), None) if pattern.matches(other_host) => eyre!(
+                        "Host pattern {} of {} also matches the host {} of {}",
+                        host,
+                        domain.email_domain,
+                        other_host,
+                        other_domain
+                    ),
+                    _ => continue,
+                };
+                overlaps.push((i, j, overlap));
+            }
+        }
+        earlier.extend(entries);
+    }
+    overlaps
+}
+
+/// The hosts and patterns allowed for a domain that an earlier domain already allows, as the
+/// indices of the domain and of the entry in its `allowed_hosts`
+pub fn duplicate_hosts(domains: &[Domain]) -> Vec<(usize, usize, Report)> {
+    let mut owners: HashMap<&str, usize> = HashMap::new();
+    let mut duplicates = vec![];
+    for (i, domain) in domains.iter().enumerate() {
+        for (j, host) in domain.allowed_hosts.iter().enumerate() {
+            match owners.get(host.as_str()) {
+                Some(&other) if other != i => duplicates.push((
+                    i,
+                    j,
+                    eyre!(
+                        "Host {} is allowed for both {} and {}",
+                        host,
+                        domains[other].email_domain,
+                        domain.email_domain
+                    ),
+                )),
+                Some(_) => {}
+                None => {
+                    owners.insert(host, i);
+                }
+            }
+        }
+    }
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One domain per entry of `hosts`, named `domain<index>.example`
+    fn domains(hosts: &[&[&str]]) -> Vec<Domain> {
+        (hosts.iter().enumerate())
+            .map(|(i, hosts)| {
+                toml::from_str(&format!(
+                    r#"
+                    email_domain = "domain{}.example"
+                    display_name = "Example"
+                    display_short_name = "Example"
+                    ssl_chain = "chain.pem"
+                    ssl_key = "key.pem"
+                    allowed_hosts = {:?}
+                    smtp = {{ host = "smtp.example", port = 465, socket_type = "SSL" }}
+                    "#,
+                    i, hosts
+                ))
+                .unwrap()
+            })
+            .collect()
+    }
+
+    fn pattern(host: &str) -> HostPattern {
+        HostPattern::parse(host).unwrap().unwrap()
+    }
+
+    #[test]
+    fn pattern_matches_one_or_more_labels() {
+        let pattern = pattern("autoconfig.*");
+        assert!(pattern.matches("autoconfig.example"));
+        assert!(pattern.matches("autoconfig.mail.example"));
+        assert!(!pattern.matches("autoconfig"));
+        assert!(!pattern.matches("autodiscover.example"));
+
+        let pattern = self::pattern("*.mail.example.net");
+        assert!(pattern.matches("a.mail.example.net"));
+        assert!(pattern.matches("a.b.mail.example.net"));
+        assert!(!pattern.matches("mail.example.net"));
+        assert!(!pattern.matches("a.mail.example.org"));
+
+        let pattern = self::pattern("autoconfig.*.example");
+        assert!(pattern.matches("autoconfig.a.example"));
+        assert!(!pattern.matches("autoconfig.example"));
+    }
+
+    #[test]
+    fn plain_hosts_are_no_patterns() {
+        assert!(HostPattern::parse("autoconfig.example").unwrap().is_none());
+    }
+
+    #[test]
+    fn rejected_patterns() {
+        for host in [
+            "*",
+            "*.*.example",
+            "auto*.example",
+            "*..example",
+            "autoconfig.*.",
+        ] {
+            assert!(validate_host(host).is_err(), "{} was accepted", host);
+        }
+    }
+
+    #[test]
+    fn exact_hosts_win_over_patterns() {
+        let domains = domains(&[&["*.example"], &["autoconfig.example"]]);
+        let host_map = HostMap::new(&domains).unwrap();
+        assert_eq!(host_map.get("autoconfig.example"), Some(1));
+        assert_eq!(host_map.get("autodiscover.example"), Some(0));
+        assert_eq!(host_map.get("example"), None);
+    }
+
+    #[test]
+    fn more_specific_patterns_win() {
+        let domains = domains(&[
+            &["autoconfig.*"],
+            &["*.example"],
+            &["*.mail.example"],
+            &["autoconfig.*.mail.example"],
+        ]);
+        let host_map = HostMap::new(&domains).unwrap();
+        assert_eq!(host_map.get("autoconfig.other"), Some(0));
+        // The longer suffix wins over the prefix with as many fixed labels
+        assert_eq!(host_map.get("autoconfig.example"), Some(1));
+        assert_eq!(host_map.get("a.mail.example"), Some(2));
+        assert_eq!(host_map.get("autoconfig.a.mail.example"), Some(3));
+    }
+
+    #[test]
+    fn duplicate_hosts_are_rejected() {
+        let domains = domains(&[&["autoconfig.*"], &["autoconfig.*"]]);
+        assert!(HostMap::new(&domains).is_err());
+        let duplicates = duplicate_hosts(&domains);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!((duplicates[0].0, duplicates[0].1), (1, 0));
+    }
+
+    #[test]
+    fn overlapping_patterns() {
+        assert!(pattern("autoconfig.*").overlaps(&pattern("*.example")));
+        assert!(pattern("*.mail.example").overlaps(&pattern("*.example")));
+        assert!(pattern("a.*.c").overlaps(&pattern("*.b.c")));
+        assert!(!pattern("*.example").overlaps(&pattern("*.example.org")));
+        assert!(!pattern("autoconfig.*").overlaps(&pattern("autodiscover.*")));
+        assert!(!pattern("a.*.c").overlaps(&pattern("b.*")));
+        // Only hosts with three labels could match both
+        assert!(pattern("a.*").overlaps(&pattern("*.a.b")));
+        assert!(!pattern("a.*.c").overlaps(&pattern("b.*.c")));
+    }
+
+    #[test]
+    fn overlapping_hosts_of_other_domains() {
+        let domains = domains(&[
+            &["autoconfig.*", "mail.example"],
+            &["*.example", "autoconfig.other", "autoconfig.*"],
+            &["*.example.org", "mail.example.org"],
+        ]);
+        let overlaps: Vec<(usize, usize)> = overlapping_hosts(&domains)
+            .into_iter()
+            .map(|(i, j, _)| (i, j))
+            .collect();
+        // The duplicate autoconfig.* and the pattern matching a host of its own domain are left out
+        assert_eq!(overlaps, [(1, 0), (1, 0), (1, 1), (2, 0), (2, 0)]);
+    }
+}
@@ -1,9 +1,9 @@
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use std::time::Duration;
-use std::{convert::Infallible, sync::Arc};
 
 use clap::{Parser, Subcommand};
 use color_eyre::Report;
@@ -13,13 +13,11 @@ use eyre::eyre;
 use eyre::Result;
 use futures::TryStreamExt;
 use global_state::Notify;
-use hyper::{
-    service::{make_service_fn, service_fn},
-    Body, Request, Response, Server,
-};
+use hyper::{Body, Request, Response};
 use hyper::{Method, StatusCode, Uri};
 use notify::{RecommendedWatcher, Watcher};
 use openssl::pkcs7::{Pkcs7, Pkcs7Flags};
+use sd_notify::NotifyState;
 use serde::Serialize;
 use tera::Context;
 use tokio::io::BufReader;
@@ -28,15 +26,34 @@ use tokio::signal;
 use tokio::sync::mpsc::{channel, Sender};
 use tokio::task::spawn_blocking;
 use tokio_util::io::StreamReader;
-use tracing::{debug, error, info, warn};
-use util::get_email_from_request;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use util::{email_domain, get_autodiscover_request, normalize_domain, AutodiscoverRequest};
 use uuid::Uuid;
 
-use crate::config::Domain;
+use crate::acme::ACME_HTTP_CHALLENGE_PATH;
+use crate::autodiscover::{
+    autodiscover_json, autodiscover_json_email, insert_error, is_autodiscover_json_path, ErrorCode,
+    MOBILESYNC_RESPONSE_SCHEMA, OUTLOOK_RESPONSE_SCHEMA,
+};
+use crate::config::{ClientFormat, Config, Domain, RedirectTarget, UnknownHost};
+use crate::dns::DnsFormat;
+use crate::forwarded::RequestOrigin;
 use crate::global_state::GlobalState;
+use crate::server::{ClientAddr, CloseConnection, Listeners};
 
+mod acme;
+mod autodiscover;
+mod check;
 mod config;
+mod dns;
+mod forwarded;
 mod global_state;
+mod host_map;
+mod metrics;
+mod proxy_protocol;
+mod server;
+mod systemd;
+mod upgrade;
 mod util;
 
 #[derive(Parser)]
@@ -52,7 +69,34 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Run the server
-    Run,
+    Run {
+        /// Run as systemd service: use the sockets passed via LISTEN_FDS and report readiness,
+        /// reloads, shutdown and watchdog pings via sd_notify
+        #[clap(long)]
+        systemd: bool,
+    },
+    /// Print the DNS records needed for mail clients to discover the configured domains
+    Dns {
+        /// Host name this server is reachable under, the discovery hosts are pointed to it
+        #[clap(short, long)]
+        target: String,
+        /// Output format
+        #[clap(short, long, arg_enum, default_value_t = DnsFormat::Bind)]
+        format: DnsFormat,
+        /// TTL of the generated records
+        #[clap(long, default_value_t = 3600)]
+        ttl: u32,
+        /// Also generate MTA-STS and TLS-RPT records for domains without an MTA-STS policy (as stubs)
+        #[clap(long)]
+        mta_sts: bool,
+    },
+    /// Load the config without serving it and report all problems found, exits with an error
+    /// status if there are errors
+    Check {
+        /// Fail on warnings as well
+        #[clap(long)]
+        strict: bool,
+    },
 }
 
 async fn shutdown_signal() {
@@ -102,11 +146,11 @@ impl Payload {
         let identifier = parts.join(".");
         let description = format!(
             "Install this profile to autoconfigure your email on {}",
-            domain.email_domain
+            domain.email_domain_unicode
         );
         let display_name = "Email Autoconfiguration".to_owned();
         let ptype = "Configuration".to_owned();
-        let organization = format!("{} mail provider", domain.email_domain);
+        let organization = format!("{} mail provider", domain.email_domain_unicode);
         Self {
             uuid,
             identifier,
@@ -125,9 +169,23 @@ impl Payload {
         this.identifier.push_str(&format!(".{}", name));
         this
     }
+    fn new_dav(domain: &Domain, name: &str, email_address: &str, kind: &str) -> Self {
+        let mut this = Self::new_domain(domain, name, email_address);
+        this.ptype = format!("com.apple.{}.account", kind);
+        this.identifier.push_str(&format!(".{}", kind));
+        this
+    }
+}
+
+/// All payloads configuring the accounts of one email address
+#[derive(Debug, Serialize)]
+struct AccountPayloads {
+    mail: Payload,
+    caldav: Option<Payload>,
+    carddav: Option<Payload>,
 }
 
-fn get_mails(uri: &Uri, domain: &Domain) -> Result<HashMap<String, Payload>> {
+fn get_mails(uri: &Uri, domain: &Domain) -> Result<HashMap<String, AccountPayloads>> {
     let mut emails = HashMap::new();
     for (key, value) in
         form_urlencoded::parse(uri.query().ok_or(eyre!("query missing"))?.as_bytes())
@@ -137,174 +195,433 @@ fn get_mails(uri: &Uri, domain: &Domain) -> Result<HashMap<String, Payload>> {
 
         ensure!(!emails.contains_key(parsed.as_ref()), "duplicate email");
         ensure!(
-            parsed.domain() == domain.email_domain,
+            normalize_domain(parsed.domain()).ok().as_ref() == Some(&domain.email_domain),
             "email {} does not belong to this server",
             value
         );
-        let payload = Payload::new_domain(domain, parsed.local_part(), parsed.as_ref());
-        emails.insert(parsed.to_string(), payload);
+        let (name, email) = (parsed.local_part(), parsed.as_ref());
+        let payloads = AccountPayloads {
+            mail: Payload::new_domain(domain, name, email),
+            caldav: domain
+                .caldav
+                .as_ref()
+                .map(|_| Payload::new_dav(domain, name, email, "caldav")),
+            carddav: domain
+                .carddav
+                .as_ref()
+                .map(|_| Payload::new_dav(domain, name, email, "carddav")),
+        };
+        emails.insert(parsed.to_string(), payloads);
     }
     Ok(emails)
 }
 
-async fn serve(global_state: Arc<GlobalState>, req: Request<Body>) -> Result<Response<Body>> {
-    let global_state = global_state.load();
-    let host = req.headers()[hyper::header::HOST].to_str()?;
-    let host = host.split_once(':').map(|(s, _)| s).unwrap_or(host);
-    if let Some(domain_idx) = global_state.host_map.get(host).copied() {
-        let domain = &global_state.config.domains[domain_idx];
-        let mut context = Context::new();
-        context.insert("domain", &domain);
-        match &req.uri().path().to_lowercase()[..] {
-            "/generate_profile" => {
-                if req.method() == Method::GET {
-                    let rendered = global_state
-                        .templates
-                        .render("apple_email.html", &context)?;
+/// Inserts the servers usable by `format` into the context, both as priority ordered lists and
+/// the highest-priority ones for clients that only take a single server.
+/// The config validation ensures that there is at least one server of each kind for every format.
+fn insert_servers(context: &mut Context, domain: &Domain, format: ClientFormat) {
+    let incoming_servers = domain.incoming_servers(format);
+    let outgoing_servers = domain.outgoing_servers(format);
+    context.insert("incoming", &incoming_servers[0]);
+    context.insert("outgoing", &outgoing_servers[0]);
+    context.insert("incoming_servers", &incoming_servers);
+    context.insert("outgoing_servers", &outgoing_servers);
+}
 
-                    let response = Response::builder().header("Content-Type", "text/html");
-                    Ok(response.body(rendered.into())?)
-                } else {
-                    Ok(Response::builder()
-                        .status(StatusCode::METHOD_NOT_ALLOWED)
-                        .body(Body::empty())?)
+/// Whether `client` may read the metrics, local clients on Unix sockets always may
+fn metrics_allowed(config: &Config, client: ClientAddr) -> bool {
+    match client.0 {
+        Some(addr) => (config.metrics_allowed.iter()).any(|net| net.contains(&addr.ip())),
+        None => true,
+    }
+}
+
+/// The address a request asks for, which selects the domain on shared hosts
+fn request_email(
+    path: &str,
+    uri: &Uri,
+    autodiscover_request: Option<&AutodiscoverRequest>,
+) -> Option<String> {
+    let query_param = |name| {
+        form_urlencoded::parse(uri.query().unwrap_or_default().as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    match path {
+        "/mail/config-v1.1.xml" => query_param("emailaddress"),
+        "/email.mobileconfig" => query_param("email"),
+        "/autodiscover/autodiscover.xml" => autodiscover_request.map(|r| r.email.clone()),
+        path if is_autodiscover_json_path(path) => autodiscover_json_email(uri),
+        _ => None,
+    }
+}
+
+async fn read_autodiscover_request(body: Body) -> Result<AutodiscoverRequest> {
+    let buf_read = BufReader::new(StreamReader::new(body.map_err(|err| {
+        error!("Request stream err: {}", err);
+        tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "eof")
+    })));
+    get_autodiscover_request(buf_read).await
+}
+
+async fn serve(
+    global_state: Arc<GlobalState>,
+    mut req: Request<Body>,
+    origin: RequestOrigin,
+) -> Result<Response<Body>> {
+    // ACME validates every host of a domain, so this is answered independently of the host
+    if let Some(token) = req.uri().path().strip_prefix(ACME_HTTP_CHALLENGE_PATH) {
+        return Ok(
+            match global_state.acme_challenges.http_key_authorization(token) {
+                Some(key_authorization) => Response::builder()
+                    .header("Content-Type", "application/octet-stream")
+                    .body(key_authorization.into())?,
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())?,
+            },
+        );
+    }
+    let metrics = &global_state.metrics;
+    let global_state = global_state.load();
+    // Like the challenges answered on every host, so that scrapers can use the server's address.
+    // Access is limited by client instead.
+    if global_state.config.metrics_path.as_deref() == Some(req.uri().path())
+        && metrics_allowed(&global_state.config, origin.client)
+    {
+        return Ok(Response::builder()
+            .header("Content-Type", "text/plain; version=0.0.4")
+            .body(metrics.render(&global_state).into())?);
+    }
+    let host = match &origin.host {
+        Some(host) => host,
+        None => {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())?)
+        }
+    };
+    let path = req.uri().path().to_lowercase();
+    let mut autodiscover_request = None;
+    let domain_idx = if global_state.config.shared_hosts.contains(host) {
+        // Autodiscover names the address only in the body
+        if path == "/autodiscover/autodiscover.xml" && req.method() == Method::POST {
+            let body = std::mem::replace(req.body_mut(), Body::empty());
+            autodiscover_request = Some(read_autodiscover_request(body).await?);
+        }
+        match request_email(&path, req.uri(), autodiscover_request.as_ref()) {
+            Some(email) => {
+                let email_domain = email_domain(&email);
+                let domain_idx = (global_state.config.domains.iter())
+                    .position(|domain| Some(&domain.email_domain) == email_domain.as_ref());
+                // The host is known, only the address is not served here
+                match (domain_idx, email_domain) {
+                    (Some(domain_idx), _) => Some(domain_idx),
+                    (None, Some(_)) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::FORBIDDEN)
+                            .body(Body::empty())?)
+                    }
+                    (None, None) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::empty())?)
+                    }
                 }
             }
-            "/email.mobileconfig" => {
-                // Apple Mail
-                match *req.method() {
-                    Method::GET => {
-                        let emails = match get_mails(req.uri(), domain) {
-                            Ok(v) => v,
-                            Err(err) => {
-                                return Ok(Response::builder()
-                                    .status(StatusCode::BAD_REQUEST)
-                                    .body(format!("Error: {:#}", err).into())?);
-                            }
-                        };
-                        debug!("Got emails: {:?}", emails);
-                        context.insert("plist_payload", &Payload::new_plist(domain));
-                        let payloads: HashMap<String, Payload> = emails.into_iter().collect();
-                        context.insert("payloads", &payloads);
-
-                        let rendered_config = global_state
-                            .templates
-                            .render("apple_config.plist", &context)?;
-                        let global_state = global_state.clone();
-                        let signed = spawn_blocking(move || -> Result<Vec<u8>> {
-                            let domain = &global_state.config.domains[domain_idx];
-                            let certs = global_state
-                                .cert_map
-                                .get(&domain.email_domain)
-                                .ok_or_else( || eyre!("No cert for domain {}", domain.email_domain))?;
-                            let singed = Pkcs7::sign(
-                                &certs.cert,
-                                &certs.key,
-                                &certs.chain,
-                                rendered_config.as_bytes(),
-                                Pkcs7Flags::empty(),
-                            )?
-                            .to_der()?;
-                            Ok(singed)
-                        })
-                        .await??;
-
-                        let response = Response::builder().header("Content-Type", "application/pkcs7-mime; smime-type=signed-data; name=email.mobileconfig").header("Content-Disposition", "attachment; filename=email.mobileconfig");
-                        Ok(response.body(signed.into())?)
-                    }
-                    _ => Ok(Response::builder()
-                        .status(StatusCode::METHOD_NOT_ALLOWED)
-                        .body(Body::empty())?),
+            None => global_state.host_map.get(host),
+        }
+    } else {
+        global_state.host_map.get(host)
+    };
+    let domain_idx = match domain_idx {
+        Some(domain_idx) => domain_idx,
+        None => {
+            metrics.count_unknown_host(host);
+            match &global_state.config.unknown_host {
+                UnknownHost::Forbidden => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::empty())?)
+                }
+                UnknownHost::Domain { .. } => global_state
+                    .config
+                    .unknown_host_domain()
+                    .ok_or_else(|| eyre!("Unknown host domain is missing"))?,
+                UnknownHost::Redirect { host: target } => {
+                    let path = req.uri().path_and_query().map(|p| p.as_str());
+                    let location = format!("{}://{}{}", origin.scheme, target, path.unwrap_or("/"));
+                    return Ok(Response::builder()
+                        .status(StatusCode::PERMANENT_REDIRECT)
+                        .header(hyper::header::LOCATION, location)
+                        .body(Body::empty())?);
+                }
+                UnknownHost::ErrorPage { template, status } => {
+                    let mut context = Context::new();
+                    context.insert("host", host);
+                    context.insert("host_unicode", &idna::domain_to_unicode(host).0);
+                    let rendered = global_state.templates.render(template, &context)?;
+                    return Ok(Response::builder()
+                        .status(*status)
+                        .header("Content-Type", "text/html")
+                        .body(rendered.into())?);
                 }
+                UnknownHost::Close => {
+                    let mut response = Response::new(Body::empty());
+                    response.extensions_mut().insert(CloseConnection);
+                    return Ok(response);
+                }
+            }
+        }
+    };
+    let domain = &global_state.config.domains[domain_idx];
+    let mut context = Context::new();
+    context.insert("domain", &domain);
+    match &path[..] {
+        "/generate_profile" => {
+            if req.method() == Method::GET {
+                let rendered = global_state
+                    .templates
+                    .render("apple_email.html", &context)?;
+
+                let response = Response::builder().header("Content-Type", "text/html");
+                Ok(response.body(rendered.into())?)
+            } else {
+                Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())?)
             }
+        }
+        "/email.mobileconfig" => {
+            // Apple Mail
+            match *req.method() {
+                Method::GET => {
+                    let emails = match get_mails(req.uri(), domain) {
+                        Ok(v) => v,
+                        Err(err) => {
+                            return Ok(Response::builder()
+                                .status(StatusCode::BAD_REQUEST)
+                                .body(format!("Error: {:#}", err).into())?);
+                        }
+                    };
+                    debug!("Got emails: {:?}", emails);
+                    context.insert("plist_payload", &Payload::new_plist(domain));
+                    let payloads: HashMap<String, AccountPayloads> = emails.into_iter().collect();
+                    context.insert("payloads", &payloads);
+                    insert_servers(&mut context, domain, ClientFormat::Apple);
+                    for (kind, dav) in [("caldav", &domain.caldav), ("carddav", &domain.carddav)] {
+                        if let Some(dav) = dav {
+                            context.insert(format!("{}_username", kind), domain.dav_username(dav));
+                        }
+                    }
 
-            "/mail/config-v1.1.xml" => {
-                // Thunderbird
-                if req.method() == Method::GET {
                     let rendered_config = global_state
                         .templates
-                        .render("thunderbolt_config.xml", &context)?;
+                        .render("apple_config.plist", &context)?;
+                    let global_state = global_state.clone();
+                    let signed = spawn_blocking(move || -> Result<Vec<u8>> {
+                        let domain = &global_state.config.domains[domain_idx];
+                        let certs = global_state
+                            .cert_map
+                            .get(&domain.email_domain)
+                            .ok_or_else(|| eyre!("No cert for domain {}", domain.email_domain))?;
+                        let singed = Pkcs7::sign(
+                            &certs.cert,
+                            &certs.key,
+                            &certs.chain,
+                            rendered_config.as_bytes(),
+                            Pkcs7Flags::empty(),
+                        )?
+                        .to_der()?;
+                        Ok(singed)
+                    })
+                    .await??;
 
-                    let response = Response::builder().header("Content-Type", "text/xml");
-                    Ok(response.body(rendered_config.into())?)
-                } else {
-                    Ok(Response::builder()
-                        .status(StatusCode::METHOD_NOT_ALLOWED)
-                        .body(Body::empty())?)
+                    let response = Response::builder().header("Content-Type", "application/pkcs7-mime; smime-type=signed-data; name=email.mobileconfig").header("Content-Disposition", "attachment; filename=email.mobileconfig");
+                    Ok(response.body(signed.into())?)
                 }
+                _ => Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())?),
             }
-            "/autodiscover/autodiscover.xml" => {
-                // Microsoft mail
-                if req.method() == Method::POST {
-                    let buf_read =
-                        BufReader::new(StreamReader::new(req.into_body().map_err(|err| {
-                            error!("Request stream err: {}", err);
-                            tokio::io::Error::new(tokio::io::ErrorKind::UnexpectedEof, "eof")
-                        })));
-                    let email = get_email_from_request(buf_read).await?;
-                    context.insert("email", &email);
-                    let rendered_config = global_state
-                        .templates
-                        .render("microsoft_config.xml", &context)?;
+        }
 
-                    let response = Response::builder().header("Content-Type", "text/xml");
-                    Ok(response.body(rendered_config.into())?)
-                } else {
-                    Ok(Response::builder()
-                        .status(StatusCode::METHOD_NOT_ALLOWED)
-                        .body(Body::empty())?)
+        "/mail/config-v1.1.xml" => {
+            // Thunderbird
+            if req.method() == Method::GET {
+                insert_servers(&mut context, domain, ClientFormat::Thunderbird);
+                let rendered_config = global_state
+                    .templates
+                    .render("thunderbolt_config.xml", &context)?;
+
+                let response = Response::builder().header("Content-Type", "text/xml");
+                Ok(response.body(rendered_config.into())?)
+            } else {
+                Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())?)
+            }
+        }
+        "/autodiscover/autodiscover.xml" => {
+            // Microsoft mail
+            if req.method() == Method::POST {
+                let request = match autodiscover_request {
+                    Some(request) => request,
+                    None => read_autodiscover_request(req.into_body()).await?,
+                };
+                let email = request.email;
+                context.insert("email", &email);
+                let redirect = domain.redirect_for(&email);
+                let template = match request
+                    .response_schema
+                    .as_deref()
+                    .unwrap_or(OUTLOOK_RESPONSE_SCHEMA)
+                {
+                    OUTLOOK_RESPONSE_SCHEMA => {
+                        match redirect {
+                            Some(redirect) => context.insert("redirect", &redirect),
+                            None => {
+                                insert_servers(&mut context, domain, ClientFormat::Autodiscover)
+                            }
+                        }
+                        "microsoft_config.xml"
+                    }
+                    MOBILESYNC_RESPONSE_SCHEMA => match redirect {
+                        Some(RedirectTarget::RedirectAddr(addr)) => {
+                            context.insert("redirect_addr", &addr);
+                            "microsoft_mobilesync.xml"
+                        }
+                        None if domain.activesync_url.is_some() => "microsoft_mobilesync.xml",
+                        // MobileSync responses cannot redirect to another URL
+                        _ => insert_error(&mut context, ErrorCode::ProviderUnavailable),
+                    },
+                    schema => {
+                        debug!("Unsupported Autodiscover response schema: {}", schema);
+                        insert_error(&mut context, ErrorCode::ProviderUnavailable)
+                    }
+                };
+                let rendered_config = global_state.templates.render(template, &context)?;
+
+                let response = Response::builder().header("Content-Type", "text/xml");
+                Ok(response.body(rendered_config.into())?)
+            } else {
+                Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())?)
+            }
+        }
+        "/.well-known/mta-sts.txt" => {
+            // MTA-STS policies are only served on their dedicated host
+            match &domain.mta_sts {
+                Some(mta_sts) if *host == format!("mta-sts.{}", domain.email_domain) => {
+                    if req.method() == Method::GET {
+                        let response = Response::builder().header("Content-Type", "text/plain");
+                        Ok(response.body(mta_sts.policy().into())?)
+                    } else {
+                        Ok(Response::builder()
+                            .status(StatusCode::METHOD_NOT_ALLOWED)
+                            .body(Body::empty())?)
+                    }
                 }
+                _ => Ok(Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())?),
             }
-            _ => Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .body(Body::empty())?),
         }
-    } else {
-        Ok(Response::builder()
-            .status(StatusCode::FORBIDDEN)
-            .body(Body::empty())?)
+        path if is_autodiscover_json_path(path) => {
+            // Microsoft Autodiscover v2
+            if req.method() == Method::GET {
+                autodiscover_json(req.uri(), &format!("{}://{}", origin.scheme, host), domain)
+            } else {
+                Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::empty())?)
+            }
+        }
+        _ => Ok(Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::empty())?),
     }
 }
 
 async fn service(
     global_state: Arc<GlobalState>,
     req: Request<Body>,
-) -> Result<Response<Body>, Infallible> {
-    match serve(global_state, req).await {
-        Ok(response) => Ok(response),
-        Err(err) => {
-            error!("Unexpected error while processing request: {:#}", err);
-            Ok(Response::builder()
-                .status(StatusCode::INTERNAL_SERVER_ERROR)
-                .body(Body::empty())
-                .unwrap())
+) -> Result<Response<Body>, CloseConnection> {
+    let peer = req
+        .extensions()
+        .get::<ClientAddr>()
+        .copied()
+        .unwrap_or_default();
+    let origin = RequestOrigin::new(
+        req.headers(),
+        peer,
+        &global_state.load().config.trusted_proxies,
+    );
+    let span = info_span!(
+        "request",
+        client = %origin.client,
+        method = %req.method(),
+        path = req.uri().path()
+    );
+    async move {
+        match serve(global_state, req, origin).await {
+            Ok(response) if response.extensions().get::<CloseConnection>().is_some() => {
+                Err(CloseConnection)
+            }
+            Ok(response) => Ok(response),
+            Err(err) => {
+                error!("Unexpected error while processing request: {:#}", err);
+                Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::empty())
+                    .unwrap())
+            }
         }
     }
+    .instrument(span)
+    .await
 }
 
-async fn run(global_state: Arc<GlobalState>) -> Result<()> {
-    println!("Server started, you can gracefully stop this server with Ctrl-C. Reload its config by sending the SIGUSR1 signal.");
-    let gs = global_state.clone();
-    let make_service = make_service_fn(move |_conn| {
-        let gs = gs.clone();
-        async move {
-            Ok::<_, eyre::Report>(service_fn(move |req| {
-                let global_state = gs.clone();
-                async move { service(global_state, req).await }
-            }))
-        }
-    });
-
-    let global_state = global_state.load();
-    let socket_addr = global_state.config.socket_address;
-    // drop here so that the first config does not have to live in memory indefenitely after a reload
-    drop(global_state);
-
-    Server::bind(&socket_addr)
-        .serve(make_service)
-        .with_graceful_shutdown(shutdown_signal())
-        .await?;
+async fn run(global_state: Arc<GlobalState>, systemd: bool) -> Result<()> {
+    let (tracker, mut finished) = channel::<()>(1);
+    let mut listeners = Listeners::new(tracker);
+    if systemd {
+        systemd::enable();
+        listeners = listeners.with_socket_activated(systemd::listen_fds()?);
+    }
+    let (handed_over, socket_activated) = upgrade::inherited_listeners()?;
+    listeners = listeners
+        .with_inherited(handed_over)
+        .with_socket_activated(socket_activated);
+    global_state.start_listeners(listeners).await?;
+    upgrade::notify_ready()?;
+    println!("Server started, you can gracefully stop this server with Ctrl-C. Reload its config by sending the SIGUSR1 signal. Upgrade to a new executable by sending the SIGUSR2 signal.");
+    // After an upgrade this process replaces the previous one as main process of the service
+    systemd::notify(&[NotifyState::MainPid(std::process::id()), NotifyState::Ready]);
 
+    let mut upgrade_signal = signal::unix::signal(signal::unix::SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            _ = shutdown_signal() => {
+                systemd::notify(&[NotifyState::Stopping]);
+                break;
+            }
+            _ = upgrade_signal.recv() => {
+                info!("Upgrading, handing the listeners over to a new process...");
+                match upgrade::spawn_successor(global_state.listener_sockets().await).await {
+                    Ok(()) => {
+                        info!("The new process is serving, finishing open connections");
+                        break;
+                    }
+                    Err(err) => error!("Upgrade failed, continuing to serve: {:#}", err),
+                }
+            }
+        }
+    }
+    global_state.stop_listeners().await;
+    // resolves once all listeners and connections are done
+    finished.recv().await;
     Ok(())
 }
 
@@ -344,17 +661,16 @@ fn watch_for_changes(
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    color_eyre::install()?;
-
-    let cli = Cli::parse();
-    let config_path = cli.config.into();
+/// Loads the global state and sets up reloading it on file changes, if configured
+async fn load_state(config_path: PathBuf) -> Result<Arc<GlobalState>> {
     let (send, recv) = channel(1);
     let global_state = GlobalState::new(config_path, Some(recv)).await?;
     let gs = global_state.load();
 
+    // Requests and renews the certificates of domains without configured ones
+    acme::spawn(global_state.clone(), send.clone());
+    global_state.clone().spawn_expiry_check();
+
     // Watch for changes and reload server (mainly for cert changes)
     if let Some(watch_path) = &gs.config.watch_path {
         let watch_path = watch_path.to_owned();
@@ -368,9 +684,34 @@ async fn main() -> Result<()> {
             }
         });
     }
+    Ok(global_state)
+}
 
+#[tokio::main]
+async fn main() -> Result<()> {
+    // Logs go to stderr, so that they do not mix with the output of subcommands like `dns`
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
+    color_eyre::install()?;
+
+    let cli = Cli::parse();
     match cli.command {
-        Commands::Run => run(global_state).await?,
+        Commands::Run { systemd } => run(load_state(cli.config.into()).await?, systemd).await?,
+        Commands::Dns {
+            target,
+            format,
+            ttl,
+            mta_sts,
+        } => {
+            let config = Config::load(&cli.config).await?;
+            print!("{}", dns::export(&config, format, &target, ttl, mta_sts)?);
+        }
+        Commands::Check { strict } => {
+            if !check::check(Path::new(&cli.config), strict).await? {
+                std::process::exit(1);
+            }
+        }
     }
     Ok(())
 }
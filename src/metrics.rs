@@ -0,0 +1,95 @@
+use std::{
+    collections::HashMap,
+    fmt::{Display, Write},
+    sync::Mutex,
+};
+
+use tracing::warn;
+
+use crate::global_state::GlobalStateData;
+
+/// Distinct unknown hosts that are counted on their own, further ones are counted together
+const MAX_UNKNOWN_HOSTS: usize = 1000;
+
+/// Counters kept across reloads, served in the Prometheus text format
+#[derive(Default)]
+pub struct Metrics {
+    unknown_hosts: Mutex<UnknownHosts>,
+}
+
+#[derive(Default)]
+struct UnknownHosts {
+    hosts: HashMap<String, u64>,
+    /// Requests for hosts beyond [`MAX_UNKNOWN_HOSTS`]
+    other: u64,
+}
+
+impl Metrics {
+    /// Counts a request for a host that is not allowed for any domain
+    pub fn count_unknown_host(&self, host: &str) {
+        let mut unknown_hosts = self.unknown_hosts.lock().unwrap();
+        if let Some(count) = unknown_hosts.hosts.get_mut(host) {
+            *count += 1;
+        } else if unknown_hosts.hosts.len() < MAX_UNKNOWN_HOSTS {
+            warn!("Request for unknown host {}, is a domain missing?", host);
+            unknown_hosts.hosts.insert(host.to_owned(), 1);
+        } else {
+            unknown_hosts.other += 1;
+        }
+    }
+
+    /// The counters, followed by the certificate expiry of the domains of `state`
+    pub fn render(&self, state: &GlobalStateData) -> String {
+        let mut out = String::new();
+        let unknown_hosts = self.unknown_hosts.lock().unwrap();
+        out.push_str("# HELP mail_autoconfig_unknown_host_requests_total Requests for hosts not allowed for any domain\n");
+        out.push_str("# TYPE mail_autoconfig_unknown_host_requests_total counter\n");
+        let mut hosts: Vec<_> = unknown_hosts.hosts.iter().collect();
+        hosts.sort();
+        for (host, count) in hosts {
+            metric_line(
+                &mut out,
+                "mail_autoconfig_unknown_host_requests_total",
+                &[("host", host)],
+                count,
+            );
+        }
+        if unknown_hosts.other > 0 {
+            metric_line(
+                &mut out,
+                "mail_autoconfig_unknown_host_requests_total",
+                &[("host", "(other)")],
+                unknown_hosts.other,
+            );
+        }
+        out.push_str("# HELP mail_autoconfig_certificate_expiry_days Days until the certificate of a domain expires\n");
+        out.push_str("# TYPE mail_autoconfig_certificate_expiry_days gauge\n");
+        for domain in &state.config.domains {
+            let days = state
+                .cert_map
+                .get(&domain.email_domain)
+                .and_then(|certs| certs.days_until_expiry().ok());
+            if let Some(days) = days {
+                metric_line(
+                    &mut out,
+                    "mail_autoconfig_certificate_expiry_days",
+                    &[("domain", &domain.email_domain)],
+                    days,
+                );
+            }
+        }
+        out
+    }
+}
+
+fn metric_line(out: &mut String, name: &str, labels: &[(&str, &str)], value: impl Display) {
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(key, value)| {
+            let value = value.replace('\\', "\\\\").replace('"', "\\\"");
+            format!("{}=\"{}\"", key, value)
+        })
+        .collect();
+    // writing to a String cannot fail
+    let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), value);
+}
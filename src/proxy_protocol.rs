@@ -0,0 +1,237 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use eyre::{bail, ensure, eyre, Result};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Signature starting a version 2 header
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of a version 1 header, including the CRLF
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol header (version 1 or 2) from the start of `stream` and returns the
+/// client address it contains, or `None` for connections the proxy made itself (`LOCAL`,
+/// `UNKNOWN`) or of an address family other than TCP over IPv4/IPv6.
+/// Reads exactly the header, so that the stream can be used for TLS or HTTP afterwards.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0; 12];
+    stream.read_exact(&mut start[..6]).await?;
+    if &start[..6] == b"PROXY " {
+        return read_v1(stream).await;
+    }
+    stream.read_exact(&mut start[6..]).await?;
+    ensure!(
+        start == V2_SIGNATURE,
+        "Connection without PROXY protocol header"
+    );
+    read_v2(stream).await
+}
+
+/// Parses the rest of a version 1 header, e.g. `TCP4 192.0.2.1 198.51.100.1 56324 443\r\n`
+async fn read_v1<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = vec![];
+    while !line.ends_with(b"\r\n") {
+        ensure!(
+            line.len() < V1_MAX_LENGTH - 6,
+            "PROXY protocol v1 header is too long"
+        );
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["UNKNOWN", ..] => Ok(None),
+        ["TCP4" | "TCP6", source, _destination, source_port, _destination_port] => {
+            Ok(Some(SocketAddr::new(source.parse()?, source_port.parse()?)))
+        }
+        _ => bail!("Invalid PROXY protocol v1 header {:?}", line),
+    }
+}
+
+/// Parses the rest of a version 2 header after the signature
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>>
+where
+    S: AsyncRead + Unpin,
+{
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let length = stream.read_u16().await? as usize;
+    let mut payload = vec![0; length];
+    stream.read_exact(&mut payload).await?;
+    ensure!(
+        version_command >> 4 == 2,
+        "Unsupported PROXY protocol version {}",
+        version_command >> 4
+    );
+    match version_command & 0x0f {
+        // LOCAL
+        0x0 => return Ok(None),
+        // PROXY
+        0x1 => {}
+        command => bail!("Unknown PROXY protocol v2 command {}", command),
+    }
+    let address_length = match family {
+        // TCP over IPv4
+        0x11 => 12,
+        // TCP over IPv6
+        0x21 => 36,
+        _ => return Ok(None),
+    };
+    // Additional TLVs after the addresses are ignored
+    let addresses = payload
+        .get(..address_length)
+        .ok_or_else(|| eyre!("PROXY protocol v2 header is too short"))?;
+    let source = match family {
+        0x11 => IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4])?)),
+        _ => IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16])?)),
+    };
+    let port_offset = address_length - 4;
+    let source_port = u16::from_be_bytes([addresses[port_offset], addresses[port_offset + 1]]);
+    Ok(Some(SocketAddr::new(source, source_port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Reads the header from `input` and returns the address and what is left of the stream
+    async fn read(input: &[u8]) -> Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = input;
+        let address = read_header(&mut stream).await?;
+        Ok((address, stream.to_vec()))
+    }
+
+    fn v2_header(version_command: u8, family: u8, payload: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([version_command, family]);
+        header.extend((payload.len() as u16).to_be_bytes());
+        header.extend(payload);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (address, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET")
+            .await
+            .unwrap();
+        assert_eq!(address, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        let (address, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n")
+            .await
+            .unwrap();
+        assert_eq!(address, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        let (address, rest) = read(b"PROXY UNKNOWN ff ff\r\nGET").await.unwrap();
+        assert_eq!(address, None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v1_invalid() {
+        assert!(read(b"PROXY TCP4 192.0.2.1 56324\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 port 443\r\n")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_truncated() {
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1").await.is_err());
+        assert!(read(b"PROX").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_too_long() {
+        let mut input = b"PROXY UNKNOWN ".to_vec();
+        input.extend([b'f'; V1_MAX_LENGTH]);
+        input.extend(b"\r\n");
+        assert!(read(&input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let mut input = v2_header(0x20, 0x00, &[]);
+        input.extend(b"GET");
+        let (address, rest) = read(&input).await.unwrap();
+        assert_eq!(address, None);
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv4() {
+        let mut payload = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        payload.extend(56324u16.to_be_bytes());
+        payload.extend(443u16.to_be_bytes());
+        // A TLV after the addresses
+        payload.extend([0x04, 0x00, 0x01, 0xff]);
+        let mut input = v2_header(0x21, 0x11, &payload);
+        input.extend(b"GET");
+        let (address, rest) = read(&input).await.unwrap();
+        assert_eq!(address, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET");
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_ipv6() {
+        let source: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let destination: Ipv6Addr = "2001:db8::2".parse().unwrap();
+        let mut payload = source.octets().to_vec();
+        payload.extend(destination.octets());
+        payload.extend(56324u16.to_be_bytes());
+        payload.extend(443u16.to_be_bytes());
+        let (address, _) = read(&v2_header(0x21, 0x21, &payload)).await.unwrap();
+        assert_eq!(address, Some("[2001:db8::1]:56324".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_proxy_other_family() {
+        // UDP over IPv4
+        let (address, rest) = read(&v2_header(0x21, 0x12, &[0; 12])).await.unwrap();
+        assert_eq!(address, None);
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_invalid() {
+        // Version 1 in a version 2 header
+        assert!(read(&v2_header(0x11, 0x11, &[0; 12])).await.is_err());
+        // Unknown command
+        assert!(read(&v2_header(0x22, 0x11, &[0; 12])).await.is_err());
+        // Addresses shorter than the family needs
+        assert!(read(&v2_header(0x21, 0x11, &[0; 8])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_truncated() {
+        let input = v2_header(0x21, 0x11, &[0; 12]);
+        assert!(read(&input[..input.len() - 1]).await.is_err());
+        assert!(read(&input[..14]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_length_beyond_stream() {
+        let mut input = v2_header(0x21, 0x11, &[0; 12]);
+        input[14..16].copy_from_slice(&u16::MAX.to_be_bytes());
+        assert!(read(&input).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn wrong_signature() {
+        let mut input = v2_header(0x21, 0x11, &[0; 12]);
+        input[11] = b'X';
+        assert!(read(&input).await.is_err());
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+    }
+}
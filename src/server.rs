@@ -0,0 +1,625 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::{self, Display},
+    fs::Permissions,
+    io,
+    net::SocketAddr,
+    os::unix::{
+        fs::{FileTypeExt, PermissionsExt},
+        io::{AsRawFd, FromRawFd, RawFd},
+    },
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::{bail, ensure, eyre, Result};
+use futures::pin_mut;
+use hyper::{server::conn::Http, service::service_fn};
+use ipnet::IpNet;
+use nix::unistd::{chown, Gid, Group, Uid, User};
+use openssl::{
+    pkey::{PKeyRef, Private},
+    ssl::{
+        select_next_proto, AlpnError, NameType, SniError, Ssl, SslAcceptor, SslAlert, SslMethod,
+        SslRef,
+    },
+    x509::X509Ref,
+};
+use socket2::{Domain, Socket, Type};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    sync::mpsc::Sender,
+    task::JoinHandle,
+    time::timeout,
+};
+use tokio_openssl::SslStream;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    acme::ACME_TLS_ALPN_PROTOCOL,
+    config::{ListenerBind, ListenerConfig, ListenerProtocol},
+    global_state::GlobalState,
+    proxy_protocol, service,
+};
+
+/// Time a client has to complete the TLS handshake
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Time a proxy has to send the PROXY protocol header
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Builds a TLS acceptor that picks the certificate of the domain whose `allowed_hosts` contain the
+/// SNI host name. As the certificates are looked up in the current state on every handshake,
+/// reloaded certificates are used right away.
+/// Connections negotiating `acme-tls/1` get the certificate of a pending TLS-ALPN-01 challenge.
+fn tls_acceptor(global_state: Arc<GlobalState>) -> Result<SslAcceptor> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    let state = global_state.clone();
+    builder.set_servername_callback(move |ssl, _alert: &mut SslAlert| {
+        let data = state.load();
+        let server_name = ssl.servername(NameType::HOST_NAME).map(str::to_lowercase);
+        let certs = data
+            .certs_for_server_name(server_name.as_deref())
+            .ok_or(SniError::ALERT_FATAL)?;
+        set_certificate(ssl, &certs.cert, &certs.key, certs.chain.iter().skip(1)).map_err(|err| {
+            error!("Could not select TLS certificate: {:#}", err);
+            SniError::ALERT_FATAL
+        })
+    });
+    builder.set_alpn_select_callback(move |ssl, client_protocols| {
+        let protocol = select_next_proto(ACME_ALPN_PROTOCOLS, client_protocols)
+            .filter(|p| *p == ACME_TLS_ALPN_PROTOCOL)
+            .ok_or(AlpnError::NOACK)?;
+        let server_name = ssl
+            .servername(NameType::HOST_NAME)
+            .map(str::to_lowercase)
+            .ok_or(AlpnError::ALERT_FATAL)?;
+        let (cert, key) = global_state
+            .acme_challenges
+            .tls_alpn_certificate(&server_name)
+            .ok_or(AlpnError::ALERT_FATAL)?;
+        debug!("Answering TLS-ALPN-01 challenge for {}", server_name);
+        set_certificate(ssl, &cert, &key, std::iter::empty()).map_err(|err| {
+            error!("Could not set ACME challenge certificate: {:#}", err);
+            AlpnError::ALERT_FATAL
+        })?;
+        Ok(protocol)
+    });
+    Ok(builder.build())
+}
+
+/// Wire format list of the protocols answered via ALPN
+const ACME_ALPN_PROTOCOLS: &[u8] = b"\x0aacme-tls/1";
+
+fn set_certificate<'a>(
+    ssl: &mut SslRef,
+    cert: &X509Ref,
+    key: &PKeyRef<Private>,
+    intermediates: impl Iterator<Item = &'a X509Ref>,
+) -> Result<()> {
+    ssl.set_certificate(cert)?;
+    ssl.set_private_key(key)?;
+    for intermediate in intermediates {
+        ssl.add_chain_cert(intermediate.to_owned())?;
+    }
+    Ok(())
+}
+
+/// A bound listening socket
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Binds the socket of `bind`. IPv6 sockets only accept IPv6 connections, so that the same
+    /// port can also be bound on an IPv4 address. A stale Unix socket is replaced, any other file at
+    /// the path fails the bind.
+    pub fn bind(bind: &ListenerBind) -> Result<Self> {
+        match bind {
+            ListenerBind::Tcp { address } => {
+                let socket = Socket::new(Domain::for_address(*address), Type::STREAM, None)?;
+                if address.is_ipv6() {
+                    socket.set_only_v6(true)?;
+                }
+                socket.set_reuse_address(true)?;
+                socket.set_nonblocking(true)?;
+                socket.bind(&(*address).into())?;
+                socket.listen(1024)?;
+                Ok(Self::Tcp(TcpListener::from_std(socket.into())?))
+            }
+            ListenerBind::Unix {
+                path,
+                mode,
+                owner,
+                group,
+            } => {
+                match std::fs::symlink_metadata(path) {
+                    Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+                    Ok(_) => bail!("{} exists and is not a socket", path.display()),
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                    Err(err) => return Err(err.into()),
+                }
+                let listener = UnixListener::bind(path)?;
+                set_permissions(path, *mode, owner.as_deref(), group.as_deref())?;
+                Ok(Self::Unix(listener))
+            }
+        }
+    }
+
+    /// Takes over a listening socket passed in by another process
+    pub fn from_fd(fd: RawFd) -> Result<(Self, ListenerBind)> {
+        // Safety: the socket was handed to this process to listen on and is not used otherwise
+        let socket = unsafe { Socket::from_raw_fd(fd) };
+        socket.set_nonblocking(true)?;
+        match socket.local_addr()?.as_socket() {
+            Some(address) => Ok((
+                Self::Tcp(TcpListener::from_std(socket.into())?),
+                ListenerBind::Tcp { address },
+            )),
+            None => {
+                let listener: std::os::unix::net::UnixListener = socket.into();
+                let path = listener
+                    .local_addr()?
+                    .as_pathname()
+                    .ok_or_else(|| eyre!("Socket {} is neither a TCP nor a Unix socket", fd))?
+                    .to_owned();
+                Ok((
+                    Self::Unix(UnixListener::from_std(listener)?),
+                    ListenerBind::Unix {
+                        path,
+                        mode: None,
+                        owner: None,
+                        group: None,
+                    },
+                ))
+            }
+        }
+    }
+
+    fn local_addr(&self) -> String {
+        match self {
+            Self::Tcp(listener) => format!("{:?}", listener.local_addr()),
+            Self::Unix(listener) => format!("{:?}", listener.local_addr()),
+        }
+    }
+}
+
+impl AsRawFd for Listener {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            Self::Unix(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+fn set_permissions(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<&str>,
+    group: Option<&str>,
+) -> Result<()> {
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+    }
+    let uid = owner.map(user_id).transpose()?;
+    let gid = group.map(group_id).transpose()?;
+    if uid.is_some() || gid.is_some() {
+        chown(path, uid, gid)?;
+    }
+    Ok(())
+}
+
+/// Removes the socket file of a Unix listener that is no longer served
+fn remove_socket_file(bind: &ListenerBind) {
+    if let ListenerBind::Unix { path, .. } = bind {
+        if let Err(err) = std::fs::remove_file(path) {
+            warn!("Could not remove socket {}: {:#}", path.display(), err);
+        }
+    }
+}
+
+fn user_id(user: &str) -> Result<Uid> {
+    if let Ok(uid) = user.parse() {
+        return Ok(Uid::from_raw(uid));
+    }
+    Ok(User::from_name(user)?
+        .ok_or_else(|| eyre!("Unknown user {}", user))?
+        .uid)
+}
+
+fn group_id(group: &str) -> Result<Gid> {
+    if let Ok(gid) = group.parse() {
+        return Ok(Gid::from_raw(gid));
+    }
+    Ok(Group::from_name(group)?
+        .ok_or_else(|| eyre!("Unknown group {}", group))?
+        .gid)
+}
+
+/// The running listeners, which are updated to the configured ones on every reload
+pub struct Listeners {
+    running: HashMap<String, RunningListener>,
+    /// Sockets passed in by the service manager or the previous process, only used by the first
+    /// update
+    inherited: HashMap<String, Listener>,
+    /// Sockets passed in by systemd socket activation. systemd keeps them open and connections
+    /// queue up on them, so they stay served as long as this process runs, with the protocol they
+    /// were passed with unless a listener is configured for the same socket.
+    socket_activated: Vec<ListenerConfig>,
+    /// Shared by all TLS listeners, created with the first one
+    acceptor: Option<Arc<SslAcceptor>>,
+    shutdown: CancellationToken,
+    tracker: Sender<()>,
+}
+
+/// A listening socket as handed over to another process
+pub struct ListenerSocket {
+    pub fd: RawFd,
+    pub protocol: ListenerProtocol,
+    /// Passed in by systemd socket activation, so the other process keeps serving it as well
+    pub socket_activated: bool,
+}
+
+struct RunningListener {
+    config: ListenerConfig,
+    fd: RawFd,
+    /// Stops only this listener and its connections
+    stop: CancellationToken,
+    task: JoinHandle<Listener>,
+}
+
+impl Listeners {
+    /// `tracker` is passed on to every listener and connection task, see [`serve_listener`]
+    pub fn new(tracker: Sender<()>) -> Self {
+        Self {
+            running: HashMap::new(),
+            inherited: HashMap::new(),
+            socket_activated: vec![],
+            acceptor: None,
+            shutdown: CancellationToken::new(),
+            tracker,
+        }
+    }
+
+    /// Uses the given sockets instead of binding new ones for listeners on the same addresses in
+    /// the first update. Sockets that are not configured then are closed.
+    pub fn with_inherited(mut self, inherited: Vec<(ListenerConfig, Listener)>) -> Self {
+        for (config, listener) in inherited {
+            self.inherited.insert(config.bind.to_string(), listener);
+        }
+        self
+    }
+
+    /// Serves the sockets passed in by systemd socket activation, see `socket_activated`
+    pub fn with_socket_activated(mut self, sockets: Vec<(ListenerConfig, Listener)>) -> Self {
+        for (config, listener) in sockets {
+            self.inherited.insert(config.bind.to_string(), listener);
+            self.socket_activated.push(config);
+        }
+        self
+    }
+
+    /// The sockets of the running listeners, to hand them over to another process
+    pub fn sockets(&self) -> Vec<ListenerSocket> {
+        self.running
+            .iter()
+            .map(|(key, running)| ListenerSocket {
+                fd: running.fd,
+                protocol: running.config.protocol,
+                socket_activated: self
+                    .socket_activated
+                    .iter()
+                    .any(|config| config.bind.to_string() == *key),
+            })
+            .collect()
+    }
+
+    /// Stops all listeners and shuts their connections down gracefully
+    pub fn shutdown(self) {
+        self.shutdown.cancel();
+    }
+
+    /// Starts the configured listeners that are not running yet, drains the ones that are no
+    /// longer configured and restarts the ones whose settings changed on the same socket.
+    /// Fails if any listener could not be started, after all others have been updated; one that
+    /// could not be restarted keeps running with its previous settings.
+    pub async fn update(
+        &mut self,
+        mut configs: Vec<ListenerConfig>,
+        global_state: &Arc<GlobalState>,
+    ) -> Result<()> {
+        for activated in &self.socket_activated {
+            let key = activated.bind.to_string();
+            if !configs.iter().any(|config| config.bind.to_string() == key) {
+                configs.push(activated.clone());
+            }
+        }
+        let configured: HashSet<String> = configs.iter().map(|c| c.bind.to_string()).collect();
+        let removed: Vec<String> = self
+            .running
+            .keys()
+            .filter(|key| !configured.contains(*key))
+            .cloned()
+            .collect();
+        for key in removed {
+            let running = self.running.remove(&key).unwrap();
+            info!("Draining listener {}", key);
+            running.stop.cancel();
+            remove_socket_file(&running.config.bind);
+        }
+
+        let mut errors = vec![];
+        for config in configs {
+            let key = config.bind.to_string();
+            let running = match self.running.remove(&key) {
+                Some(running) if running.config == config => {
+                    self.running.insert(key, running);
+                    continue;
+                }
+                running => running,
+            };
+            // Prepared before the socket is touched, so that a failure leaves it as it is
+            let tls = match config.protocol {
+                ListenerProtocol::Http => None,
+                ListenerProtocol::Https => match self.acceptor(global_state) {
+                    Ok(acceptor) => Some(acceptor),
+                    Err(err) => {
+                        error!("Could not set up TLS for listener {}: {:#}", key, err);
+                        if let Some(running) = running {
+                            self.running.insert(key.clone(), running);
+                        }
+                        errors.push(key);
+                        continue;
+                    }
+                },
+            };
+            let listener = match running {
+                // Takes the socket over, so that it is not closed in between
+                Some(running) => {
+                    if let ListenerBind::Unix {
+                        path,
+                        mode,
+                        owner,
+                        group,
+                    } = &config.bind
+                    {
+                        if let Err(err) =
+                            set_permissions(path, *mode, owner.as_deref(), group.as_deref())
+                        {
+                            error!("Could not restart listener {}: {:#}", key, err);
+                            self.running.insert(key.clone(), running);
+                            errors.push(key);
+                            continue;
+                        }
+                    }
+                    running.stop.cancel();
+                    match running.task.await {
+                        Ok(listener) => {
+                            info!("Restarting listener {} ({:?})", key, config.protocol);
+                            listener
+                        }
+                        Err(err) => {
+                            error!("Could not restart listener {}: {:#}", key, err);
+                            errors.push(key);
+                            continue;
+                        }
+                    }
+                }
+                None => match self
+                    .inherited
+                    .remove(&key)
+                    .map(Ok)
+                    .unwrap_or_else(|| Listener::bind(&config.bind))
+                {
+                    Ok(listener) => {
+                        info!("Listening on {} ({:?})", key, config.protocol);
+                        listener
+                    }
+                    Err(err) => {
+                        error!("Could not listen on {}: {:#}", key, err);
+                        errors.push(key);
+                        continue;
+                    }
+                },
+            };
+            let fd = listener.as_raw_fd();
+            let stop = self.shutdown.child_token();
+            let context = ListenerContext {
+                tls,
+                proxy_protocol_trusted: config
+                    .proxy_protocol
+                    .then(|| Arc::new(config.proxy_protocol_trusted.clone())),
+                global_state: global_state.clone(),
+                shutdown: stop.clone(),
+                _tracker: self.tracker.clone(),
+            };
+            let task = tokio::spawn(serve_listener(listener, context));
+            self.running.insert(
+                key,
+                RunningListener {
+                    config,
+                    fd,
+                    stop,
+                    task,
+                },
+            );
+        }
+        for key in self.inherited.drain().map(|(key, _)| key) {
+            info!("Closed inherited socket {}, it is not configured", key);
+        }
+        ensure!(
+            !self.running.is_empty(),
+            "No listener is configured (socket_address or [[listeners]])"
+        );
+        ensure!(
+            errors.is_empty(),
+            "Could not listen on {}",
+            errors.join(", ")
+        );
+        Ok(())
+    }
+
+    fn acceptor(&mut self, global_state: &Arc<GlobalState>) -> Result<Arc<SslAcceptor>> {
+        if let Some(acceptor) = &self.acceptor {
+            return Ok(acceptor.clone());
+        }
+        let acceptor = Arc::new(tls_acceptor(global_state.clone())?);
+        self.acceptor = Some(acceptor.clone());
+        Ok(acceptor)
+    }
+}
+
+/// Accepts connections on `listener` until the `shutdown` of the context is cancelled, then shuts
+/// down the open connections gracefully and gives the listener back.
+/// The context (and with it the tracker) is held by the listener and every connection task, so
+/// that all of them have finished once every clone of the tracker has been dropped.
+async fn serve_listener(listener: Listener, context: ListenerContext) -> Listener {
+    loop {
+        let accepted = tokio::select! {
+            accepted = accept(&listener) => accepted,
+            _ = context.shutdown.cancelled() => break,
+        };
+        match accepted {
+            Ok((Connection::Tcp(stream), peer)) => spawn_connection(stream, peer, context.clone()),
+            Ok((Connection::Unix(stream), peer)) => spawn_connection(stream, peer, context.clone()),
+            Err(err) => error!("Could not accept connection: {:#}", err),
+        }
+    }
+    info!("Stopped accepting connections on {}", listener.local_addr());
+    listener
+}
+
+/// What the connections of a listener share
+#[derive(Clone)]
+struct ListenerContext {
+    tls: Option<Arc<SslAcceptor>>,
+    /// Peers whose PROXY protocol header is read, if it is enabled
+    proxy_protocol_trusted: Option<Arc<Vec<IpNet>>>,
+    global_state: Arc<GlobalState>,
+    shutdown: CancellationToken,
+    /// Only held, so that all clones are dropped once the listener and its connections are done
+    _tracker: Sender<()>,
+}
+
+impl ListenerContext {
+    /// Whether a PROXY protocol header is expected from `peer`, Unix socket peers are local and
+    /// always trusted. Other peers are served directly, so they cannot pretend to be someone else.
+    fn expects_proxy_header(&self, peer: Option<SocketAddr>) -> bool {
+        match (&self.proxy_protocol_trusted, peer) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(trusted), Some(peer)) => trusted.iter().any(|net| net.contains(&peer.ip())),
+        }
+    }
+}
+
+/// Returned by the service to close the connection without answering the request, which `serve`
+/// asks for by adding it to the response extensions
+#[derive(Clone, Copy, Debug, thiserror::Error)]
+#[error("Connection closed without a response")]
+pub struct CloseConnection;
+
+/// The address of the client a request came from, as reported by a trusted proxy via PROXY
+/// protocol or the peer address. `None` for local clients on Unix sockets.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientAddr(pub Option<SocketAddr>);
+
+impl Display for ClientAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0 {
+            Some(addr) => write!(f, "{}", addr),
+            None => write!(f, "local"),
+        }
+    }
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+async fn accept(listener: &Listener) -> io::Result<(Connection, Option<SocketAddr>)> {
+    match listener {
+        Listener::Tcp(listener) => {
+            let (stream, peer) = listener.accept().await?;
+            Ok((Connection::Tcp(stream), Some(peer)))
+        }
+        Listener::Unix(listener) => {
+            let (stream, _) = listener.accept().await?;
+            Ok((Connection::Unix(stream), None))
+        }
+    }
+}
+
+fn spawn_connection<S>(mut stream: S, peer: Option<SocketAddr>, context: ListenerContext)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let peer = ClientAddr(peer);
+    debug!("Accepted connection from {}", peer);
+    tokio::spawn(async move {
+        let result = async {
+            let client = match context.expects_proxy_header(peer.0) {
+                true => {
+                    let header = timeout(
+                        PROXY_HEADER_TIMEOUT,
+                        proxy_protocol::read_header(&mut stream),
+                    )
+                    .await
+                    .map_err(|_| eyre!("PROXY protocol header timed out"))??;
+                    // Connections the proxy makes itself, e.g. health checks, carry no address
+                    header.map(|addr| ClientAddr(Some(addr))).unwrap_or(peer)
+                }
+                false => peer,
+            };
+            match &context.tls {
+                Some(acceptor) => {
+                    let stream = tls_handshake(acceptor, stream).await?;
+                    serve_connection(stream, client, &context).await
+                }
+                None => serve_connection(stream, client, &context).await,
+            }
+        };
+        if let Err(err) = result.await {
+            debug!("Error on connection from {}: {:#}", peer, err);
+        }
+        drop(context);
+    });
+}
+
+async fn tls_handshake<S>(acceptor: &SslAcceptor, stream: S) -> Result<SslStream<S>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = SslStream::new(Ssl::new(acceptor.context())?, stream)?;
+    timeout(TLS_HANDSHAKE_TIMEOUT, Pin::new(&mut stream).accept())
+        .await
+        .map_err(|_| eyre!("TLS handshake timed out"))??;
+    Ok(stream)
+}
+
+/// Serves HTTP on `stream`, finishing the request in flight and closing the connection
+/// once `shutdown` is cancelled
+async fn serve_connection<S>(stream: S, client: ClientAddr, context: &ListenerContext) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let global_state = context.global_state.clone();
+    let service = service_fn(move |mut req| {
+        req.extensions_mut().insert(client);
+        service(global_state.clone(), req)
+    });
+    let connection = Http::new().serve_connection(stream, service);
+    pin_mut!(connection);
+    tokio::select! {
+        result = connection.as_mut() => return Ok(result?),
+        _ = context.shutdown.cancelled() => {},
+    }
+    connection.as_mut().graceful_shutdown();
+    Ok(connection.await?)
+}
@@ -0,0 +1,74 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use eyre::Result;
+use sd_notify::NotifyState;
+use tokio::time::interval;
+use tracing::{info, warn};
+
+use crate::{
+    config::{ListenerConfig, ListenerProtocol},
+    server::Listener,
+};
+
+/// Whether the service manager is notified, only set in systemd mode
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the notifications and starts sending watchdog pings if the unit has a watchdog
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+    let mut usec = 0;
+    if sd_notify::watchdog_enabled(true, &mut usec) {
+        // ping twice per timeout, as recommended by sd_watchdog_enabled(3)
+        let period = Duration::from_micros(usec) / 2;
+        info!("Sending systemd watchdog pings every {:?}", period);
+        tokio::spawn(async move {
+            let mut interval = interval(period);
+            loop {
+                interval.tick().await;
+                notify(&[NotifyState::Watchdog]);
+            }
+        });
+    }
+}
+
+/// Sends `state` to the service manager if systemd mode is enabled
+pub fn notify(state: &[NotifyState]) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Err(err) = sd_notify::notify(false, state) {
+        warn!("Could not notify systemd: {:#}", err);
+    }
+}
+
+/// Tells the service manager that a reload started, which it expects to be followed by
+/// [`NotifyState::Ready`]
+pub fn notify_reloading() {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    match NotifyState::monotonic_usec_now() {
+        Ok(now) => notify(&[NotifyState::Reloading, now]),
+        Err(err) => warn!("Could not read the monotonic clock: {:#}", err),
+    }
+}
+
+/// The sockets passed via `LISTEN_FDS`. They are served with the protocol of a configured listener
+/// on the same address, otherwise as HTTPS if their `FileDescriptorName=` is `https` and as plain
+/// HTTP if not.
+pub fn listen_fds() -> Result<Vec<(ListenerConfig, Listener)>> {
+    let mut listeners = vec![];
+    for (fd, name) in sd_notify::listen_fds_with_names(true)? {
+        let (listener, bind) = Listener::from_fd(fd)?;
+        let protocol = match &name[..] {
+            "https" => ListenerProtocol::Https,
+            _ => ListenerProtocol::Http,
+        };
+        info!("Got socket {} ({}) from systemd", bind, name);
+        listeners.push((ListenerConfig::new(bind, protocol), listener));
+    }
+    Ok(listeners)
+}
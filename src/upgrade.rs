@@ -0,0 +1,148 @@
+use std::{
+    env,
+    io::Write,
+    os::unix::{
+        io::{AsRawFd, FromRawFd, RawFd},
+        net::UnixStream as StdUnixStream,
+    },
+    process::Command,
+    time::Duration,
+};
+
+use eyre::{bail, eyre, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use tokio::{io::AsyncReadExt, net::UnixStream, time::timeout};
+use tracing::{info, warn};
+
+use crate::{
+    config::{ListenerConfig, ListenerProtocol},
+    server::{Listener, ListenerSocket},
+};
+
+type InheritedListeners = Vec<(ListenerConfig, Listener)>;
+
+/// Listening sockets handed over by the previous process, as `<fd>:<protocol>,...`, with
+/// `:systemd` appended to the sockets passed in by systemd socket activation
+const LISTEN_FDS_ENV: &str = "MAIL_AUTOCONFIG_LISTEN_FDS";
+/// Socket the new process reports its readiness on
+const READY_FD_ENV: &str = "MAIL_AUTOCONFIG_READY_FD";
+/// Time the new process has to start serving before the upgrade is given up
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Starts the executable this process was started with, with the same arguments, and hands the
+/// listening `sockets` over. Returns once the new process is serving them; if it fails to start,
+/// it is killed and this process keeps serving.
+pub async fn spawn_successor(sockets: Vec<ListenerSocket>) -> Result<()> {
+    let mut args = env::args_os();
+    let program = args.next().ok_or_else(|| eyre!("Unknown executable"))?;
+    let (ready, ready_child) = StdUnixStream::pair()?;
+
+    let inherited: Vec<RawFd> = sockets
+        .iter()
+        .map(|socket| socket.fd)
+        .chain([ready_child.as_raw_fd()])
+        .collect();
+    let listen_fds = sockets
+        .iter()
+        .map(|socket| {
+            let suffix = if socket.socket_activated {
+                ":systemd"
+            } else {
+                ""
+            };
+            format!("{}:{}{}", socket.fd, protocol_name(socket.protocol), suffix)
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut command = Command::new(&program);
+    command
+        .args(args)
+        .env(LISTEN_FDS_ENV, listen_fds)
+        .env(READY_FD_ENV, ready_child.as_raw_fd().to_string());
+
+    // The sockets are only inherited while close-on-exec is cleared
+    for fd in &inherited {
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::empty()))?;
+    }
+    let spawned = command.spawn();
+    for fd in &inherited {
+        fcntl(*fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    }
+    drop(ready_child);
+    let mut child = spawned?;
+    info!(
+        "Started {:?} (pid {}), waiting for it to serve",
+        program,
+        child.id()
+    );
+
+    ready.set_nonblocking(true)?;
+    let mut ready = UnixStream::from_std(ready)?;
+    let mut buf = [0];
+    match timeout(READY_TIMEOUT, ready.read(&mut buf)).await {
+        Ok(Ok(1)) => Ok(()),
+        result => {
+            if let Err(err) = child.kill() {
+                warn!("Could not kill the new process: {:#}", err);
+            }
+            // reap it, so that no zombie is left behind
+            let _ = child.wait();
+            match result {
+                Err(_) => bail!("The new process did not start serving in time"),
+                _ => bail!("The new process exited before it started serving"),
+            }
+        }
+    }
+}
+
+/// The sockets handed over by the previous process, if this process was started by
+/// [`spawn_successor`]: the ones of its listeners and the ones systemd passed in by socket
+/// activation
+pub fn inherited_listeners() -> Result<(InheritedListeners, InheritedListeners)> {
+    let listen_fds = match env::var(LISTEN_FDS_ENV) {
+        Ok(listen_fds) => listen_fds,
+        Err(_) => return Ok((vec![], vec![])),
+    };
+    env::remove_var(LISTEN_FDS_ENV);
+    let (mut listeners, mut socket_activated) = (vec![], vec![]);
+    for entry in listen_fds.split(',').filter(|e| !e.is_empty()) {
+        let (fd, protocol) = entry
+            .split_once(':')
+            .ok_or_else(|| eyre!("Invalid {} entry {:?}", LISTEN_FDS_ENV, entry))?;
+        let (protocol, activated) = match protocol.strip_suffix(":systemd") {
+            Some(protocol) => (protocol, true),
+            None => (protocol, false),
+        };
+        let protocol = match protocol {
+            "https" => ListenerProtocol::Https,
+            _ => ListenerProtocol::Http,
+        };
+        let (listener, bind) = Listener::from_fd(fd.parse()?)?;
+        info!("Took over socket {} from the previous process", bind);
+        let inherited = (ListenerConfig::new(bind, protocol), listener);
+        if activated {
+            socket_activated.push(inherited);
+        } else {
+            listeners.push(inherited);
+        }
+    }
+    Ok((listeners, socket_activated))
+}
+
+/// Tells the previous process that this one is serving, so that it can shut down
+pub fn notify_ready() -> Result<()> {
+    if let Ok(fd) = env::var(READY_FD_ENV) {
+        env::remove_var(READY_FD_ENV);
+        // Safety: the socket was handed to this process for exactly this
+        let mut ready = unsafe { StdUnixStream::from_raw_fd(fd.parse()?) };
+        ready.write_all(&[1])?;
+    }
+    Ok(())
+}
+
+fn protocol_name(protocol: ListenerProtocol) -> &'static str {
+    match protocol {
+        ListenerProtocol::Http => "http",
+        ListenerProtocol::Https => "https",
+    }
+}
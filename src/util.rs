@@ -1,36 +1,125 @@
-use eyre::{bail, ensure, Result};
+use std::collections::HashMap;
+
+use eyre::{bail, ensure, eyre, Result};
 use futures::pin_mut;
 use rxml::{AsyncEventReadExt, AsyncParser, ResolvedEvent};
+use tera::{try_get_value, Value};
 use tokio::io::AsyncBufRead;
 
-pub async fn get_email_from_request(xml: impl AsyncBufRead) -> Result<String> {
+use crate::config::expand_username;
+
+/// Matches `text` against `pattern`, in which `*` stands for any (possibly empty) sequence
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => match text.strip_prefix(prefix) {
+            Some(text) => (0..=text.len())
+                .filter(|&i| text.is_char_boundary(i))
+                .any(|i| glob_match(rest, &text[i..])),
+            None => false,
+        },
+    }
+}
+
+/// Brings a host or domain name into the form it is compared in: lowercase A-labels (IDNA/UTS-46)
+/// without a trailing dot. `*` labels of host patterns are kept.
+pub fn normalize_domain(domain: &str) -> Result<String> {
+    let domain = domain.strip_suffix('.').unwrap_or(domain);
+    let to_ascii = |domain: &str| {
+        idna::domain_to_ascii(domain).map_err(|_| eyre!("Invalid domain name {:?}", domain))
+    };
+    if !domain.contains('*') {
+        return to_ascii(domain);
+    }
+    let labels = domain
+        .split('.')
+        .map(|label| match label {
+            "*" => Ok(label.to_owned()),
+            label => to_ascii(label),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(labels.join("."))
+}
+
+/// The normalized domain of an email address, `None` if it has none or it is invalid
+pub fn email_domain(email: &str) -> Option<String> {
+    let (_, domain) = email.rsplit_once('@')?;
+    normalize_domain(domain).ok()
+}
+
+/// Tera filter that expands a login name template for the address given as `email` argument,
+/// e.g. `{{ server.username | expand_username(email=email) }}`
+pub fn expand_username_filter(value: &Value, args: &HashMap<String, Value>) -> tera::Result<Value> {
+    let template = try_get_value!("expand_username", "value", String, value);
+    let email = match args.get("email") {
+        Some(email) => try_get_value!("expand_username", "email", String, email),
+        None => {
+            return Err(tera::Error::msg(
+                "The `expand_username` filter has to have an `email` argument",
+            ))
+        }
+    };
+    Ok(Value::String(expand_username(&template, &email)))
+}
+
+/// The parts of an Autodiscover (POX) request body we care about
+#[derive(Debug)]
+pub struct AutodiscoverRequest {
+    pub email: String,
+    /// The schema the client wants its response in, if given
+    pub response_schema: Option<String>,
+}
+
+pub async fn get_autodiscover_request(xml: impl AsyncBufRead) -> Result<AutodiscoverRequest> {
     pin_mut!(xml);
-    let mut result = String::new();
+    let mut email = None;
+    let mut response_schema = None;
     let mut parser = AsyncParser::new(xml);
 
     loop {
         match parser.read().await? {
             Some(ResolvedEvent::StartElement(_, (_, name), _)) if name == "EMailAddress" => {
-                break;
+                email = Some(read_text_element(&mut parser, &name).await?);
+            }
+            Some(ResolvedEvent::StartElement(_, (_, name), _))
+                if name == "AcceptableResponseSchema" =>
+            {
+                response_schema = Some(read_text_element(&mut parser, &name).await?);
             }
             Some(_) => {}
-            None => bail!("no email found in request body"),
+            None => break,
         }
     }
+    match email {
+        Some(email) => Ok(AutodiscoverRequest {
+            email,
+            response_schema,
+        }),
+        None => bail!("no email found in request body"),
+    }
+}
+
+/// Reads the text content of the element `name` whose start was just read
+async fn read_text_element<R: AsyncBufRead + Unpin>(
+    parser: &mut AsyncParser<R>,
+    name: &str,
+) -> Result<String> {
+    let mut result = String::new();
     loop {
         match parser.read().await? {
-            Some(ResolvedEvent::StartElement(_, (_, name), _)) => {
-                bail!("No nested xml node {} allowed inside EMailAddress", name);
+            Some(ResolvedEvent::StartElement(_, (_, nested), _)) => {
+                bail!("No nested xml node {} allowed inside {}", nested, name);
             }
             Some(ResolvedEvent::EndElement(_)) => {
-                ensure!(!result.is_empty(), "no email found in request body");
-                return Ok(result);
+                let result = result.trim();
+                ensure!(!result.is_empty(), "{} in request body is empty", name);
+                return Ok(result.to_owned());
             }
             Some(ResolvedEvent::Text(_, text)) => {
                 result.push_str(&text);
             }
             Some(_) => {}
-            None => bail!("EOF in the middle of EMailAddress"),
+            None => bail!("EOF in the middle of {}", name),
         }
     }
 }